@@ -0,0 +1,38 @@
+//! Compares picking the largest results of a big scan to sorting all of them,
+//! which is what the table did on every frame before. Run with `cargo bench`.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use dirscan::scan::{self, Usage};
+
+const RESULTS: u64 = 200_000;
+const LIMIT: usize = 50;
+const RUNS: u32 = 20;
+
+/// Average time of `f` over `RUNS` runs
+fn time(mut f: impl FnMut() -> Vec<scan::DirStat>) -> Duration {
+    let started = Instant::now();
+    let rows: usize = (0..RUNS).map(|_| f().len()).sum();
+    assert!(rows > 0);
+    started.elapsed() / RUNS
+}
+
+fn main() {
+    let results: HashMap<_, _> = (0..RESULTS)
+        .map(|i| {
+            let usage = Usage {
+                bytes: i * 7919 % 100_003,
+                files: 1,
+                modified: None,
+            };
+            (format!("dir{i}/nested"), usage)
+        })
+        .collect();
+
+    let all = time(|| scan::largest(results.iter(), 0));
+    let top = time(|| scan::largest(results.iter(), LIMIT));
+    println!("sorting all {RESULTS} results: {all:?}");
+    println!("picking the {LIMIT} largest:  {top:?}");
+    println!("{:.1}x less time", all.as_secs_f64() / top.as_secs_f64());
+}
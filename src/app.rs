@@ -1,35 +1,294 @@
-use std::collections::HashMap;
-use std::path::{Component, PathBuf};
-use std::str::FromStr;
-use std::sync::{mpsc::Receiver, Arc, Mutex};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use super::scan;
+use super::chart;
+use super::export;
+use super::scan::{
+    self, Cache, DirStat, Extensions, Links, Message, ScanError, ScanOptions, Scanner, Usage,
+};
+use super::system;
 use bytesize::ByteSize;
 
-type FinalEntry = (String, u64);
-pub type Cache = HashMap<String, u64>;
+/// Aggregated size of each top-level subdirectory of the scanned path
+pub type Results = HashMap<String, Usage>;
 
+/// A scan running in the background
+pub struct Scan {
+    // Directories being scanned together
+    pub roots: Vec<String>,
+    pub rx: Receiver<Message>,
+    pub results: Results,
+    // Sum of all `results`, updated as they arrive
+    pub total: Usage,
+    // Set to stop the worker thread
+    pub cancel: Arc<AtomicBool>,
+    // Most recently processed directory and number of directories processed so far
+    pub current: String,
+    pub count: u64,
+    // Number of directories to process, if they were counted first
+    pub expected: Option<u64>,
+    // Directories that couldn't be read and why
+    pub errors: Vec<(String, String)>,
+    // Number of `errors` that are missing permissions
+    pub denied: u64,
+    // Sort the report by path, see `ScanOptions::stable_order`
+    pub stable_order: bool,
+    // Deepest level of the results, see `ScanOptions::max_depth`
+    pub max_depth: Option<usize>,
+    // Size of all files read so far by extension
+    pub extensions: Extensions,
+    // Results that are links which aren't followed
+    pub links: Links,
+    // Results that are files directly inside a root
+    pub files: HashSet<String>,
+    // Displayed entries and the result limit, order, filter and minimum size they were
+    // computed for,
+    // only recomputed when new results arrive or the display settings change
+    pub top: Visible,
+    pub top_for: Option<(usize, SortOrder, String, u64)>,
+    pub started: Instant,
+    // Total size whenever results arrived during the last `GROWTH_WINDOW`, oldest first
+    pub growth: VecDeque<(Instant, u64)>,
+}
+
+/// Results of a finished scan
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct Report {
+    pub root: String,
+    pub results: Results,
+    pub total: Usage,
+    pub errors: Vec<(String, String)>,
+    // Reports of older versions don't have these
+    #[serde(default)]
+    pub denied: u64,
+    #[serde(default)]
+    pub extensions: Extensions,
+    // Older versions kept only the paths of links, under `links`
+    #[serde(default, rename = "link_targets")]
+    pub links: Links,
+    #[serde(default)]
+    pub files: HashSet<String>,
+    // Number of directories processed and how long it took
+    pub count: u64,
+    pub elapsed: Duration,
+    // Total and free space of the volume the root is on, if known
+    pub disk: Option<(u64, u64)>,
+    pub finished: SystemTime,
+    // The scan was stopped before it read everything
+    #[serde(default)]
+    pub partial: bool,
+    // It was stopped by `ScanOptions::max_duration`, which makes it partial too
+    #[serde(default)]
+    pub time_limited: bool,
+    // Errors are in path order and exports list results by path
+    #[serde(default)]
+    pub stable_order: bool,
+}
+
+// There is only one, so the size of the largest variant doesn't matter
+#[allow(clippy::large_enum_variant)]
 pub enum ScanState {
     Idle,
-    Scanning((Receiver<Message>, Cache)),
-    Done(Vec<FinalEntry>),
+    Scanning(Scan),
+    Done(Report),
+    Error(ScanError),
+}
+
+/// Column the results are ordered by
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum SortColumn {
+    Path,
+    Size,
 }
 
-pub enum Message {
-    Intermediate(Vec<FinalEntry>),
-    Done,
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct SortOrder {
+    column: SortColumn,
+    ascending: bool,
 }
 
+impl Default for SortOrder {
+    fn default() -> Self {
+        Self {
+            column: SortColumn::Size,
+            ascending: false,
+        }
+    }
+}
+
+/// Color scheme of the window
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+enum Theme {
+    System,
+    Dark,
+    Light,
+}
+
+/// How results are shown
+#[derive(Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+enum View {
+    List,
+    // Denser list of only names and sizes
+    Compact,
+    // Bar chart of the listed results instead of the list
+    Bars,
+    // List next to a pie chart of it
+    Pie,
+    // Treemap of all results above the list
+    Treemap,
+    // Sizes of all files by extension instead of directories
+    Extensions,
+}
+
+/// Action requested from a row of the results table
+enum RowAction {
+    // Open in the file manager
+    Open(String),
+    // Scan the directory instead of the current root
+    Enter(String),
+    // Add the directory to the exclude patterns and scan again
+    Exclude(String),
+    // Open the directory containing the file in the file manager
+    Reveal(String),
+}
+
+/// Roots scanned before and after the current one, like the history of a browser
+#[derive(Default, serde::Deserialize, serde::Serialize)]
+struct History {
+    back: Vec<String>,
+    // Roots left by going back, dropped once another root is scanned
+    forward: Vec<String>,
+}
+
+impl History {
+    /// Remembers leaving `from` to scan a root that isn't in the history
+    fn leave(&mut self, from: String) {
+        self.back.push(from);
+        if self.back.len() > MAX_HISTORY {
+            self.back.remove(0);
+        }
+        self.forward.clear();
+    }
+
+    /// Root to go back to from `current`, if there is one
+    fn back(&mut self, current: Option<String>) -> Option<String> {
+        let previous = self.back.pop()?;
+        self.forward.extend(current);
+        Some(previous)
+    }
+
+    /// Root to go forward to from `current`, if `back` was used
+    fn forward(&mut self, current: Option<String>) -> Option<String> {
+        let next = self.forward.pop()?;
+        self.back.extend(current);
+        Some(next)
+    }
+}
+
+/// Quick guess of how much a scan would find
+enum Estimate {
+    Running(Receiver<io::Result<scan::Estimate>>),
+    // What was found or why that failed, shown until dismissed
+    Done(String),
+}
+
+/// Longest displayed path, longer ones are shortened in the middle
+const MAX_PATH_CHARS: usize = 60;
+
+/// Directories a scan has to get through before stopping it asks for confirmation
+const CONFIRM_STOP_AFTER: u64 = 1000;
+
+/// Most scan messages handled in one frame, the rest wait for the next one
+const MAX_MESSAGES_PER_FRAME: usize = 64;
+
+/// Time spent sampling the tree for an estimate
+const ESTIMATE_BUDGET: Duration = Duration::from_secs(1);
+
+/// How far back the growth of the total is shown during a scan
+const GROWTH_WINDOW: Duration = Duration::from_secs(30);
+
+/// Number of recently scanned paths to remember
+const MAX_RECENT: usize = 10;
+
+/// Number of roots that can be gone back to
+const MAX_HISTORY: usize = 100;
+
+/// Storage key the directory cache was saved under by older versions, see `cache_file`
+const CACHE_KEY: &str = "dir_cache";
+
+/// Storage key of the last finished scan
+const REPORT_KEY: &str = "last_report";
+
+/// Storage key of the scan before the last one of the same root
+const PREVIOUS_KEY: &str = "previous_report";
+
 /// We derive Deserialize/Serialize so we can persist app state on shutdown.
 #[derive(serde::Deserialize, serde::Serialize)]
 #[serde(default)] // if we add new fields, give them default values when deserializing old state
 pub struct TemplateApp {
     // Path in filesystem to scan
     path: String,
+    // Further directories scanned together with `path`
+    extra_roots: Vec<String>,
+    // Number of largest directories to display, 0 shows all of them
+    max_results: usize,
+    // Let the scan keep only `max_results` largest directories
+    top_only: bool,
+    // Ordering of the displayed results
+    sort: SortOrder,
+    // Show sizes in binary (KiB, MiB) instead of decimal (kB, MB) units
+    binary_units: bool,
+    // Hide results smaller than this, in MB or MiB depending on `binary_units`
+    min_size_mb: f64,
+    // What the next scans read
+    scan_options: ScanOptions,
+    // How often results are updated during a scan
+    update_interval_ms: u64,
+    // Mark results changed within this many days
+    changed_within_days: Option<u64>,
+    // Ask before stopping a scan that got far
+    confirm_stop: bool,
+    // Scan the shown root again whenever the window gets focused
+    refresh_on_focus: bool,
+    // Show how results changed since `previous`
+    compare: bool,
+    view: View,
+    theme: Theme,
+    // Roots of finished scans, most recent first
+    recent: Vec<String>,
+    // Roots to go back and forward to
+    history: History,
+    // Whether the settings window is shown
+    settings_open: bool,
+    // Only show results containing this text
+    #[serde(skip)]
+    filter: String,
     #[serde(skip)]
     state: ScanState,
-    // File size cache
-    // TODO: use this cache
+    // The finished scan before the last one of the same root, persisted under `PREVIOUS_KEY`
+    #[serde(skip)]
+    previous: Option<Report>,
+    // Message about a failed action, shown until dismissed
+    #[serde(skip)]
+    notice: Option<String>,
+    // Directory typed in to be added to `excluded_dirs`
+    #[serde(skip)]
+    new_excluded_dir: String,
+    #[serde(skip)]
+    estimate: Option<Estimate>,
+    // Stopping the scan waits for the user to confirm
+    #[serde(skip)]
+    confirming_stop: bool,
+    // Whether the window had the focus during the last frame
+    #[serde(skip)]
+    focused: bool,
+    // Directory size cache, reused between scans and persisted in `cache_file` on exit
     #[serde(skip)]
     cache: Arc<Mutex<Cache>>,
 }
@@ -38,7 +297,32 @@ impl Default for TemplateApp {
     fn default() -> Self {
         Self {
             path: "C:\\Projects\\rust".into(),
+            extra_roots: Vec::new(),
+            max_results: 10,
+            top_only: false,
+            sort: SortOrder::default(),
+            binary_units: true,
+            min_size_mb: 0.0,
+            scan_options: ScanOptions::default(),
+            update_interval_ms: 100,
+            changed_within_days: None,
+            confirm_stop: true,
+            refresh_on_focus: false,
+            compare: false,
+            view: View::List,
+            theme: Theme::System,
+            recent: Vec::new(),
+            history: History::default(),
+            settings_open: false,
+            filter: String::new(),
             state: ScanState::Idle,
+            previous: None,
+            notice: None,
+            new_excluded_dir: String::new(),
+            estimate: None,
+            confirming_stop: false,
+            // The window getting focused at startup isn't a return to it
+            focused: true,
             cache: Arc::new(Mutex::new(HashMap::new())),
         }
     }
@@ -47,12 +331,43 @@ impl Default for TemplateApp {
 impl TemplateApp {
     /// Called once before the first frame.
     pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
+        let mut app = Self::default();
         // Load previous app state (if any).
         if let Some(storage) = cc.storage {
-            return eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
+            app = eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
+            if let Some(report) = eframe::get_value(storage, REPORT_KEY) {
+                app.state = ScanState::Done(report);
+            }
+            app.previous = eframe::get_value(storage, PREVIOUS_KEY);
+        }
+        // Entries are checked against the directories, so stale ones are re-read on the next scan
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(cache) = cache_file().and_then(|file| load_cache(&file).ok()) {
+            app.cache = Arc::new(Mutex::new(cache));
         }
+        app
+    }
 
-        Default::default()
+    /// Sets all options back to their defaults, keeping the paths, the history, the results and
+    /// the cache
+    fn reset_settings(&mut self) {
+        let kept = std::mem::take(self);
+        self.path = kept.path;
+        self.extra_roots = kept.extra_roots;
+        self.sort = kept.sort;
+        self.view = kept.view;
+        self.theme = kept.theme;
+        self.recent = kept.recent;
+        self.history = kept.history;
+        self.settings_open = kept.settings_open;
+        self.filter = kept.filter;
+        self.state = kept.state;
+        self.previous = kept.previous;
+        self.notice = kept.notice;
+        self.estimate = kept.estimate;
+        self.confirming_stop = kept.confirming_stop;
+        self.focused = kept.focused;
+        self.cache = kept.cache;
     }
 }
 
@@ -60,11 +375,58 @@ impl eframe::App for TemplateApp {
     /// Called by the frame work to save state before shutdown.
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
         eframe::set_value(storage, eframe::APP_KEY, self);
+        // Older versions kept the cache here, see `on_exit`
+        storage.set_string(CACHE_KEY, String::new());
+        // Otherwise the last one stays stored
+        if let ScanState::Done(report) = &self.state {
+            eframe::set_value(storage, REPORT_KEY, report);
+        }
+        if let Some(previous) = &self.previous {
+            eframe::set_value(storage, PREVIOUS_KEY, previous);
+        }
+    }
+
+    /// Writes the cache, which takes too long for large trees to do it with every save
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(file) = cache_file() {
+            if let Err(e) = save_cache(&file, &self.cache.lock().unwrap()) {
+                log::warn!("Can't save the directory cache to {}: {e}", file.display());
+            }
+        }
     }
 
     /// Called each time the UI needs repainting, which may be many times per second.
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
-        let Self { path, state, cache } = self;
+        let Self {
+            path,
+            extra_roots,
+            max_results,
+            top_only,
+            sort,
+            binary_units,
+            min_size_mb,
+            scan_options,
+            update_interval_ms,
+            changed_within_days,
+            confirm_stop,
+            refresh_on_focus,
+            compare,
+            view,
+            theme,
+            recent,
+            history,
+            settings_open,
+            filter,
+            state,
+            previous,
+            notice,
+            new_excluded_dir,
+            estimate,
+            confirming_stop,
+            focused,
+            cache,
+        } = self;
 
         #[cfg(not(target_arch = "wasm32"))] // no File->Quit on web pages!
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
@@ -74,111 +436,1949 @@ impl eframe::App for TemplateApp {
                         frame.close();
                     }
                 });
+                ui.menu_button("Theme", |ui| {
+                    ui.radio_value(theme, Theme::System, "System");
+                    ui.radio_value(theme, Theme::Dark, "Dark");
+                    ui.radio_value(theme, Theme::Light, "Light");
+                });
             });
         });
 
+        let dark = match theme {
+            Theme::System => frame.info().system_theme != Some(eframe::Theme::Light),
+            Theme::Dark => true,
+            Theme::Light => false,
+        };
+        if ctx.style().visuals.dark_mode != dark {
+            let visuals = if dark {
+                egui::Visuals::dark()
+            } else {
+                egui::Visuals::light()
+            };
+            ctx.set_visuals(visuals);
+        }
+
+        if matches!(state, ScanState::Scanning(_) | ScanState::Done(_)) {
+            egui::TopBottomPanel::bottom("status").show(ctx, |ui| {
+                status_bar(ui, state, scan_options.two_pass, *binary_units);
+            });
+        }
+
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.heading("Dir scan");
 
+            // Set to scan `path` once the current state has been shown
+            let mut start_scan = false;
+            // Set to estimate `path` instead
+            let mut start_estimate = false;
+            // Set if `path` was taken from the history and shouldn't be added to it again
+            let mut in_history = false;
+            // Set if the scan was asked to be refreshed, which reads past the cache
+            let mut refresh = false;
             ui.horizontal(|ui| {
+                let shown = shown_root(state).map(str::to_owned);
+                let (alt_left, alt_right) = ui.input(|i| {
+                    let alt = i.modifiers.alt;
+                    let left = alt && i.key_pressed(egui::Key::ArrowLeft);
+                    (left, alt && i.key_pressed(egui::Key::ArrowRight))
+                });
+                let back = ui
+                    .add_enabled(!history.back.is_empty(), egui::Button::new("⬅"))
+                    .on_hover_text("Back (Alt+Left)");
+                let forward = ui
+                    .add_enabled(!history.forward.is_empty(), egui::Button::new("➡"))
+                    .on_hover_text("Forward (Alt+Right)");
+                let moved = if back.clicked() || alt_left {
+                    history.back(shown)
+                } else if forward.clicked() || alt_right {
+                    history.forward(shown)
+                } else {
+                    None
+                };
+                if let Some(root) = moved {
+                    *path = root;
+                    start_scan = true;
+                    in_history = true;
+                }
+
                 if ui.button("Home").clicked() {
                     if let Some(p) = dirs_next::home_dir() {
-                        *path = p.to_str().unwrap().to_owned();
+                        *path = p.to_string_lossy().into_owned();
                     }
                 }
+                ui.menu_button("Places", |ui| {
+                    for (name, place) in system::places() {
+                        if ui.button(name).clicked() {
+                            *path = place.to_string_lossy().into_owned();
+                            ui.close_menu();
+                        }
+                    }
+                });
+                let parent = Path::new(path.as_str())
+                    .parent()
+                    .filter(|p| !p.as_os_str().is_empty())
+                    .map(|p| p.to_string_lossy().into_owned());
+                let up = ui
+                    .add_enabled(parent.is_some(), egui::Button::new("⬆ Up"))
+                    .on_hover_text("Scan the parent directory");
+                if let (true, Some(parent)) = (up.clicked(), parent) {
+                    *path = parent;
+                    start_scan = true;
+                }
 
-                ui.text_edit_singleline(path);
-                if matches!(state, ScanState::Scanning(_)) {
-                    if ui.button("Stop").clicked() {
-                        *state = ScanState::Idle;
+                let edit = ui.text_edit_singleline(path);
+                // Enter makes the field lose focus, so this only fires once
+                let entered = edit.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                #[cfg(not(target_arch = "wasm32"))]
+                if ui.button("Browse…").clicked() {
+                    let mut dialog = rfd::FileDialog::new();
+                    if Path::new(path.as_str()).is_dir() {
+                        dialog = dialog.set_directory(path.as_str());
+                    }
+                    // Nothing is picked if the dialog is cancelled
+                    if let Some(folder) = dialog.pick_folder() {
+                        *path = folder.to_string_lossy().into_owned();
                     }
-                } else if ui.button("Calculate").clicked() {
-                    scan::scan_directory(ctx, state, path, cache.clone());
+                }
+                let add = ui
+                    .button("➕")
+                    .on_hover_text("Also scan this directory in the next scans");
+                if add.clicked() && !extra_roots.contains(path) {
+                    extra_roots.push(path.clone());
+                }
+                if !recent.is_empty() {
+                    egui::ComboBox::from_id_source("recent")
+                        .selected_text("Recent")
+                        .show_ui(ui, |ui| {
+                            for p in recent.iter() {
+                                ui.selectable_value(path, p.clone(), p.as_str());
+                            }
+                        });
+                }
+                if let ScanState::Scanning(scan) = state {
+                    let escaped = ui.input(|i| i.key_pressed(egui::Key::Escape));
+                    if ui.button("Stop").on_hover_text("Esc").clicked() || escaped {
+                        // Short scans are quickly started again
+                        if *confirm_stop && scan.count >= CONFIRM_STOP_AFTER {
+                            *confirming_stop = true;
+                        } else {
+                            stop_scan(state);
+                        }
+                    }
+                } else {
+                    if ui.button("Calculate").clicked() || entered {
+                        start_scan = true;
+                    }
+                    let estimating = matches!(estimate, Some(Estimate::Running(_)));
+                    start_estimate = ui
+                        .add_enabled(!estimating, egui::Button::new("Estimate"))
+                        .on_hover_text("Guess the size and scan time from a quick sample")
+                        .clicked();
                 }
             });
 
+            if !extra_roots.is_empty() {
+                let mut removed = None;
+                ui.horizontal_wrapped(|ui| {
+                    ui.label("Also scanning");
+                    for (i, root) in extra_roots.iter().enumerate() {
+                        ui.weak(root.as_str());
+                        if ui.small_button("✖").clicked() {
+                            removed = Some(i);
+                        }
+                    }
+                });
+                if let Some(i) = removed {
+                    extra_roots.remove(i);
+                }
+            }
+
+            if let (true, ScanState::Scanning(scan)) = (*confirming_stop, &*state) {
+                let mut answer = None;
+                egui::Window::new("Stop scan?")
+                    .collapsible(false)
+                    .resizable(false)
+                    .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+                    .show(ctx, |ui| {
+                        ui.label(format!(
+                            "Stop after {} directories? Nothing else is scanned.",
+                            scan.count
+                        ));
+                        ui.horizontal(|ui| {
+                            if ui.button("Stop").clicked() {
+                                answer = Some(true);
+                            }
+                            if ui.button("Continue").clicked() {
+                                answer = Some(false);
+                            }
+                        });
+                    });
+                if let Some(stop) = answer {
+                    *confirming_stop = false;
+                    if stop {
+                        stop_scan(state);
+                    }
+                }
+            } else {
+                // The scan may have finished meanwhile
+                *confirming_stop = false;
+            }
+
+            let crumbs = breadcrumbs(path);
+            let mut ancestor = None;
+            ui.horizontal_wrapped(|ui| {
+                for (i, &(segment, path)) in crumbs.iter().enumerate() {
+                    if i > 0 {
+                        ui.weak("›");
+                    }
+                    if i + 1 == crumbs.len() {
+                        ui.strong(segment);
+                    } else if ui.link(segment).clicked() {
+                        ancestor = Some(path.to_owned());
+                    }
+                }
+            });
+            if let Some(ancestor) = ancestor {
+                *path = ancestor;
+                start_scan = true;
+            }
+
+            let scanning = matches!(state, ScanState::Scanning(_));
+            let was_focused = std::mem::replace(focused, ctx.input(|i| i.focused));
+            // Only finished results are refreshed, a running scan is left alone
+            let refocused = *refresh_on_focus
+                && *focused
+                && !was_focused
+                && matches!(state, ScanState::Done(_));
+            let pressed_f5 = !scanning && ctx.input(|i| i.key_pressed(egui::Key::F5));
+            if refocused || pressed_f5 {
+                if let ScanState::Done(report) = state {
+                    *path = report.root.clone();
+                }
+                start_scan = true;
+                // Rescans on focus would read everything again each time, the cache is checked
+                // against the files anyway
+                refresh = pressed_f5;
+            }
+
+            let dropped = ctx.input(|i| i.raw.dropped_files.first().and_then(|f| f.path.clone()));
+            if let Some(dropped) = dropped {
+                if dropped.is_dir() {
+                    *path = dropped.to_string_lossy().into_owned();
+                    start_scan = true;
+                } else {
+                    let dropped = dropped.display();
+                    *notice = Some(format!(
+                        "Only directories can be scanned, {dropped} is not one"
+                    ));
+                }
+            }
+
+            if ui.button("⚙ Settings").clicked() {
+                *settings_open = !*settings_open;
+            }
+
+            match estimate {
+                Some(Estimate::Running(rx)) => match rx.try_recv() {
+                    Ok(result) => {
+                        *estimate = Some(Estimate::Done(describe_estimate(result, *binary_units)))
+                    }
+                    // The thread sends before it requests a repaint
+                    Err(TryRecvError::Empty) => {
+                        ui.horizontal(|ui| {
+                            ui.spinner();
+                            ui.weak("Estimating…");
+                        });
+                    }
+                    Err(TryRecvError::Disconnected) => *estimate = None,
+                },
+                Some(Estimate::Done(text)) => {
+                    let mut dismissed = false;
+                    ui.horizontal(|ui| {
+                        ui.label(text.as_str());
+                        dismissed = ui.small_button("✖").clicked();
+                    });
+                    if dismissed {
+                        *estimate = None;
+                    }
+                }
+                None => {}
+            }
+
+            if let Some(text) = notice {
+                let mut dismissed = false;
+                ui.horizontal(|ui| {
+                    ui.colored_label(ui.visuals().warn_fg_color, text.as_str());
+                    dismissed = ui.small_button("✖").clicked();
+                });
+                if dismissed {
+                    *notice = None;
+                }
+            }
+
+            if matches!(state, ScanState::Scanning(_) | ScanState::Done(_)) {
+                ui.horizontal(|ui| {
+                    ui.label("Filter");
+                    ui.add(egui::TextEdit::singleline(filter).hint_text("Part of a name"));
+
+                    ui.separator();
+                    ui.selectable_value(view, View::List, "List");
+                    ui.selectable_value(view, View::Compact, "Compact");
+                    ui.selectable_value(view, View::Bars, "Bars");
+                    ui.selectable_value(view, View::Pie, "Pie chart");
+                    ui.selectable_value(view, View::Treemap, "Treemap");
+                    ui.selectable_value(view, View::Extensions, "By extension");
+                });
+            }
+
+            let megabyte = if *binary_units { 1024.0 * 1024.0 } else { 1e6 };
+            let changed_after = changed_within_days
+                .and_then(|days| SystemTime::now().checked_sub(Duration::from_secs(days * 86400)));
+            let min_size = (*min_size_mb * megabyte) as u64;
+
+            let mut export_error: Option<String> = None;
+            // Failed exports of the results, which are replaced by the error
+            let mut failed_export: Option<String> = None;
+            // Set to start again as administrator
+            let mut relaunch = false;
             match state {
                 ScanState::Idle => {}
-                ScanState::Scanning((rx, results)) => {
-                    if let Ok(scan_result) = rx.try_recv() {
+                ScanState::Scanning(Scan {
+                    roots,
+                    rx,
+                    results,
+                    total,
+                    current,
+                    count,
+                    expected,
+                    errors,
+                    denied,
+                    extensions,
+                    links,
+                    files,
+                    max_depth,
+                    top,
+                    top_for,
+                    growth,
+                    ..
+                }) => {
+                    // Number of directories once the scan is done and whether it ran out of time
+                    let mut done = None;
+                    // Everything that arrived since the last frame is handled at once
+                    for handled in 0.. {
+                        // Otherwise a fast scan could keep the UI from drawing at all
+                        if handled == MAX_MESSAGES_PER_FRAME {
+                            ctx.request_repaint();
+                            break;
+                        }
+                        let scan_result = match rx.try_recv() {
+                            Ok(message) => message,
+                            // The worker always says why it stops, so this is a bug
+                            Err(TryRecvError::Disconnected) => {
+                                let e = "scan worker terminated unexpectedly".to_owned();
+                                *state = ScanState::Error(ScanError::Failed(e));
+                                return;
+                            }
+                            // The worker requests a repaint when it sends more
+                            Err(TryRecvError::Empty) => break,
+                        };
                         match scan_result {
-                            Message::Done => {
-                                let dirs = sort_results(results.iter());
-                                *state = ScanState::Done(dirs);
+                            Message::Done {
+                                count,
+                                time_limited,
+                            } => {
+                                done = Some((count, time_limited));
+                                break;
+                            }
+                            Message::Progress {
+                                current: c,
+                                count: n,
+                            } => {
+                                *current = c;
+                                *count = n;
+                            }
+                            Message::Reading { dir, entries } => {
+                                *current = format!("{dir} ({entries} entries read so far)");
+                            }
+                            Message::Failed(e) => {
+                                *state = ScanState::Error(ScanError::Failed(e));
+                                return;
+                            }
+                            Message::TooManyErrors(n) => {
+                                *state = ScanState::Error(ScanError::TooManyErrors(n));
                                 return;
                             }
+                            Message::Counted(n) => *expected = Some(n),
+                            Message::Errors(vec) => {
+                                for (dir, e) in vec {
+                                    if e.kind() == io::ErrorKind::PermissionDenied {
+                                        *denied += 1;
+                                    }
+                                    errors.push((dir, e.to_string()));
+                                }
+                            }
+                            Message::Extensions(read) => {
+                                for (extension, usage) in read {
+                                    *extensions.entry(extension).or_default() += usage;
+                                }
+                            }
                             Message::Intermediate(vec) => {
-                                let root_depth =
-                                    PathBuf::from_str(path).unwrap().components().count();
-
-                                for (p, s) in vec {
-                                    // This is a size of root dir itself, it should be added too
-                                    if &p == path {
+                                *top_for = None;
+                                for DirStat {
+                                    path: p,
+                                    usage: s,
+                                    is_symlink,
+                                    link_target,
+                                    is_dir,
+                                } in vec
+                                {
+                                    // The size of the root dir itself should be added too
+                                    let key = scan::result_key(roots, Path::new(&p), *max_depth);
+                                    let Some(key) = key else {
                                         continue;
+                                    };
+                                    if is_symlink {
+                                        links.insert(key.clone(), link_target);
                                     }
-
-                                    // Filter 2+ level subdirectories
-                                    if let Component::Normal(subdir) =
-                                        PathBuf::from(p).components().nth(root_depth).unwrap()
-                                    {
-                                        results
-                                            .entry(subdir.to_str().unwrap().to_owned())
-                                            .and_modify(|size| *size += s)
-                                            .or_insert(s);
+                                    if !is_dir {
+                                        files.insert(key.clone());
                                     }
+                                    results
+                                        .entry(key)
+                                        .and_modify(|size| *size += s)
+                                        .or_insert(s);
+                                    *total += s;
                                 }
+                                record_growth(growth, total.bytes);
+                            }
+                            Message::Top { rows, total: sum } => {
+                                *top_for = None;
+                                links.extend(
+                                    rows.iter()
+                                        .filter(|d| d.is_symlink)
+                                        .map(|d| (d.path.clone(), d.link_target.clone())),
+                                );
+                                files.extend(
+                                    rows.iter().filter(|d| !d.is_dir).map(|d| d.path.clone()),
+                                );
+                                *results = rows.into_iter().map(|d| (d.path, d.usage)).collect();
+                                *total = sum;
+                                record_growth(growth, total.bytes);
                             }
                         }
                     }
 
-                    ui.label("Scanning in progress...");
+                    if let Some((count, time_limited)) = done {
+                        remember(recent, &roots[0]);
+                        finish_scan(state, Some(count), time_limited);
+                        return;
+                    }
 
-                    // We're sorting and calculating sum every time on each repaint
-                    // TODO: needs optimisation
-                    let dirs = sort_results(results.iter());
-                    display_dirs(ui, &dirs);
+                    if let Some(expected) = expected {
+                        let fraction = *count as f32 / (*expected).max(1) as f32;
+                        ui.add(egui::ProgressBar::new(fraction).show_percentage());
+                    }
+                    ui.weak(current.as_str());
+
+                    let wanted = (*max_results, *sort, filter.clone(), min_size);
+                    if top_for.as_ref() != Some(&wanted) {
+                        *top =
+                            visible_results(results, *total, filter, min_size, *max_results, *sort);
+                        scan::mark_links(&mut top.rows, links);
+                        scan::mark_files(&mut top.rows, files);
+                        *top_for = Some(wanted);
+                    }
+                    top.changed_after = changed_after;
+                    if *view == View::Treemap {
+                        display_treemap(
+                            ui,
+                            results,
+                            *total,
+                            filter,
+                            min_size,
+                            *binary_units,
+                            false,
+                        );
+                    }
+                    if *view == View::Extensions {
+                        let (limit, binary) = (*max_results, *binary_units);
+                        display_extensions(ui, extensions, filter, min_size, limit, sort, binary);
+                    } else {
+                        display_results(ui, &roots[0], top, sort, *binary_units, false, *view);
+                    }
                 }
-                ScanState::Done(dirs) => {
-                    ui.label("Done");
-                    display_dirs(ui, dirs);
+                ScanState::Done(Report {
+                    root,
+                    results,
+                    total,
+                    errors,
+                    denied,
+                    extensions,
+                    links,
+                    files,
+                    disk,
+                    finished,
+                    partial,
+                    time_limited,
+                    stable_order: by_path,
+                    ..
+                }) => {
+                    let mut visible =
+                        visible_results(results, *total, filter, min_size, *max_results, *sort);
+                    scan::mark_links(&mut visible.rows, links);
+                    scan::mark_files(&mut visible.rows, files);
+                    visible.changed_after = changed_after;
+                    let previous = previous.as_ref().filter(|p| &p.root == root);
+                    if let (true, Some(previous)) = (*compare, previous) {
+                        visible.compare(results, &previous.results);
+                    }
+                    ui.horizontal(|ui| {
+                        if let Some(previous) = previous {
+                            let ago = age(previous.finished).unwrap_or_else(|| "moments".into());
+                            ui.checkbox(compare, "Compare to last scan")
+                                .on_hover_text(format!("Scanned {ago} ago"));
+                        }
+                        if *partial {
+                            let why = if *time_limited {
+                                "Partial (time limit reached)"
+                            } else {
+                                "Partial results (scan cancelled)"
+                            };
+                            ui.colored_label(ui.visuals().warn_fg_color, why);
+                        }
+                        if let Some(age) = age(*finished) {
+                            ui.weak(format!("Results from {age} ago"));
+                        }
+                        // The path may have been edited since
+                        if refresh_button(ui) {
+                            *path = root.clone();
+                            start_scan = true;
+                            refresh = true;
+                        }
+                        if ui
+                            .button("Copy table")
+                            .on_hover_text("Copy the shown results as text")
+                            .clicked()
+                        {
+                            let table =
+                                export::text_table(&visible.rows, visible.total, *binary_units);
+                            ui.output_mut(|o| o.copied_text = table);
+                        }
+                        #[cfg(not(target_arch = "wasm32"))]
+                        if ui.button("Export CSV").clicked() {
+                            failed_export = save_with_dialog("dirscan.csv", "CSV", |file| {
+                                export::write_csv(file, root, results, *by_path, *binary_units)
+                            })
+                            .err();
+                        }
+                        #[cfg(not(target_arch = "wasm32"))]
+                        if ui.button("Export JSON").clicked() {
+                            export_error = save_with_dialog("dirscan.json", "JSON", |file| {
+                                export::write_json(file, root, results, *total, *by_path)
+                            })
+                            .err();
+                        }
+                        #[cfg(not(target_arch = "wasm32"))]
+                        if ui.button("Save HTML report").clicked() {
+                            failed_export = save_with_dialog("dirscan.html", "HTML", |file| {
+                                export::write_html(
+                                    file,
+                                    root,
+                                    results,
+                                    *total,
+                                    *by_path,
+                                    *binary_units,
+                                )
+                            })
+                            .err();
+                        }
+                    });
+
+                    if let Some((capacity, free)) = *disk {
+                        display_disk(ui, total.bytes, capacity, free, *binary_units);
+                    }
+                    if display_errors(ui, errors, *denied) {
+                        relaunch = true;
+                    }
+                    // Otherwise an empty table could as well mean that something went wrong
+                    if results.is_empty() {
+                        ui.label(format!("No subdirectories found in {root}"));
+                    }
+                    // Paths are stable only once the scan is finished
+                    let clicked = if *view == View::Treemap {
+                        display_treemap(ui, results, *total, filter, min_size, *binary_units, true)
+                    } else {
+                        None
+                    };
+                    let action = if *view == View::Extensions {
+                        let (limit, binary) = (*max_results, *binary_units);
+                        display_extensions(ui, extensions, filter, min_size, limit, sort, binary);
+                        None
+                    } else {
+                        display_results(ui, root, &visible, sort, *binary_units, true, *view)
+                            .or(clicked)
+                    };
+                    match action {
+                        Some(RowAction::Open(name)) => {
+                            let dir = Path::new(root).join(name);
+                            if let Err(e) = system::open_in_file_manager(&dir) {
+                                *notice = Some(format!("Can't open {}: {e}", dir.display()));
+                            }
+                        }
+                        // Files can't be scanned on their own, the treemap doesn't know them apart
+                        Some(RowAction::Enter(name) | RowAction::Reveal(name))
+                            if files.contains(&name) =>
+                        {
+                            if let Err(e) = system::open_in_file_manager(Path::new(root)) {
+                                *notice = Some(format!("Can't open {root}: {e}"));
+                            }
+                        }
+                        Some(RowAction::Reveal(_)) => {}
+                        Some(RowAction::Enter(name)) => {
+                            *path = Path::new(root).join(name).to_string_lossy().into_owned();
+                            start_scan = true;
+                        }
+                        Some(RowAction::Exclude(name)) => {
+                            let dir = Path::new(root).join(name);
+                            if !scan_options.excluded_dirs.contains(&dir) {
+                                scan_options.excluded_dirs.push(dir);
+                            }
+                            *path = root.clone();
+                            start_scan = true;
+                        }
+                        None => {}
+                    }
+                }
+                ScanState::Error(e) => {
+                    ui.horizontal(|ui| {
+                        ui.colored_label(ui.visuals().error_fg_color, e.to_string());
+                        if refresh_button(ui) {
+                            start_scan = true;
+                            refresh = true;
+                        }
+                    });
+                    match e {
+                        ScanError::NotFound(missing) => {
+                            if let Some(parent) = existing_ancestor(missing) {
+                                if ui.button(format!("Scan {parent} instead")).clicked() {
+                                    *path = parent;
+                                    start_scan = true;
+                                }
+                            }
+                        }
+                        ScanError::PermissionDenied(_) => {
+                            relaunch |= elevation_hint(ui);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            if export_error.is_some() {
+                *notice = export_error;
+            }
+            if let Some(e) = failed_export {
+                *state = ScanState::Error(ScanError::Export(e));
+            }
+            if relaunch {
+                #[cfg(not(target_arch = "wasm32"))]
+                match system::relaunch_elevated() {
+                    Ok(()) => frame.close(),
+                    Err(e) => *notice = Some(format!("Can't restart as administrator: {e}")),
+                }
+            }
+            if start_scan || start_estimate {
+                let scanner = Scanner {
+                    cache: cache.clone(),
+                    flush_interval: Duration::from_millis(*update_interval_ms),
+                    top: top_only.then_some(*max_results).filter(|&n| n > 0),
+                    refresh,
+                    ..Scanner::new(scan_options)
+                };
+                // Shown in the breadcrumbs as it's going to be scanned
+                *path = scan::normalize_path(path);
+                if let (true, false, Some(from)) = (start_scan, in_history, shown_root(state)) {
+                    if from != path.as_str() {
+                        history.leave(from.to_owned());
+                    }
+                }
+                let mut roots = vec![path.clone()];
+                let extra = extra_roots.iter().map(|r| scan::normalize_path(r));
+                roots.extend(extra.filter(|r| r != path));
+                if start_scan {
+                    // Going up is possible while a scan is still running
+                    if let ScanState::Scanning(scan) = state {
+                        scan.cancel.store(true, Ordering::Relaxed);
+                    }
+                    // Results of a rescan are compared to the ones they replace, and there are
+                    // about as many of them, so room for them is made up front
+                    let left = std::mem::replace(state, ScanState::Idle);
+                    let mut expected = 0;
+                    if let ScanState::Done(report) = left {
+                        if report.root == *path {
+                            expected = report.results.len();
+                        }
+                        if report.root == *path && !report.partial {
+                            *previous = Some(report);
+                        }
+                    }
+                    scan::scan_directory(ctx, state, roots, scanner);
+                    if let ScanState::Scanning(scan) = state {
+                        scan.results.reserve(expected);
+                    }
+                } else {
+                    let (tx, rx) = mpsc::channel();
+                    let ctx = ctx.clone();
+                    thread::spawn(move || {
+                        tx.send(scanner.estimate(&roots, ESTIMATE_BUDGET)).ok();
+                        ctx.request_repaint();
+                    });
+                    *estimate = Some(Estimate::Running(rx));
+                }
+            }
+        });
+
+        // Display settings apply right away, scan settings from the next scan on
+        let mut reset = false;
+        egui::Window::new("Settings")
+            .open(settings_open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.strong("Display");
+                ui.horizontal(|ui| {
+                    ui.label("Show top");
+                    ui.add(egui::DragValue::new(max_results).clamp_range(0..=1000))
+                        .on_hover_text("0 shows all directories");
+                    ui.checkbox(top_only, "Keep only these").on_hover_text(
+                        "Faster for huge trees, but filtering and exports only see the top directories",
+                    );
+                });
+                ui.horizontal(|ui| {
+                    ui.checkbox(binary_units, "Binary units")
+                        .on_hover_text("KiB, MiB, GiB instead of kB, MB, GB");
+                    ui.label("Hide under");
+                    ui.add(
+                        egui::DragValue::new(min_size_mb)
+                            .clamp_range(0.0..=1e6)
+                            .suffix(if *binary_units { " MiB" } else { " MB" }),
+                    );
+                });
+                ui.horizontal(|ui| {
+                    let mut mark = changed_within_days.is_some();
+                    if ui.checkbox(&mut mark, "Mark changed within").changed() {
+                        *changed_within_days = mark.then_some(7);
+                    }
+                    if let Some(days) = changed_within_days {
+                        ui.add(egui::DragValue::new(days).clamp_range(1..=3650).suffix(" days"));
+                    }
+                });
+
+                ui.separator();
+                ui.strong("Scan");
+                ui.horizontal(|ui| {
+                    let mut limit_depth = scan_options.max_depth.is_some();
+                    let depth_hint = "List directories down to this many levels below the scanned \
+                        one, with everything deeper counted into them";
+                    let checkbox = ui.checkbox(&mut limit_depth, "Max depth");
+                    if checkbox.on_hover_text(depth_hint).changed() {
+                        scan_options.max_depth = limit_depth.then_some(2);
+                    }
+                    if let Some(depth) = &mut scan_options.max_depth {
+                        ui.add(egui::DragValue::new(depth).clamp_range(1..=100))
+                            .on_hover_text(depth_hint);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Exclude");
+                    ui.add(
+                        egui::TextEdit::singleline(&mut scan_options.exclude)
+                            .hint_text("node_modules, target, cache*")
+                            .desired_width(200.0),
+                    )
+                    .on_hover_text(
+                        "Comma-separated, only directories are excluded. Patterns with * or ? \
+                         match their names, others match anywhere in the path below the \
+                         scanned directory",
+                    );
+                });
+                let mut removed = None;
+                for (i, dir) in scan_options.excluded_dirs.iter().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.weak(dir.display().to_string());
+                        if ui.small_button("✖").on_hover_text("Scan it again").clicked() {
+                            removed = Some(i);
+                        }
+                    });
+                }
+                if let Some(i) = removed {
+                    scan_options.excluded_dirs.remove(i);
+                }
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::TextEdit::singleline(new_excluded_dir)
+                            .hint_text("Directory to skip")
+                            .desired_width(200.0),
+                    );
+                    let dir = PathBuf::from(scan::normalize_path(new_excluded_dir));
+                    let excluded = &mut scan_options.excluded_dirs;
+                    let add = ui.add_enabled(
+                        !dir.as_os_str().is_empty() && !excluded.contains(&dir),
+                        egui::Button::new("Exclude"),
+                    );
+                    if add.clicked() {
+                        excluded.push(dir);
+                        new_excluded_dir.clear();
+                    }
+                });
+                ui.checkbox(&mut scan_options.respect_gitignore, "Respect .gitignore");
+                ui.checkbox(&mut scan_options.follow_symlinks, "Follow symlinks")
+                    .on_hover_text("Content linked from more than one place is counted every time");
+                #[cfg(unix)]
+                ui.checkbox(&mut scan_options.one_filesystem, "One filesystem").on_hover_text(
+                    "Don't walk into other filesystems mounted below the scanned directory",
+                );
+                ui.checkbox(&mut scan_options.archives, "Look inside archives")
+                    .on_hover_text("Count the files in .zip and .tar files uncompressed, which is slower");
+                ui.checkbox(&mut scan_options.include_hidden, "Show hidden").on_hover_text(
+                    "Count files and directories starting with a dot or marked hidden",
+                );
+                ui.checkbox(&mut scan_options.on_disk, "Size on disk").on_hover_text(
+                    "Count the space allocated for files instead of their apparent size",
+                );
+                ui.horizontal(|ui| {
+                    let kilobyte = if *binary_units { 1024 } else { 1000 };
+                    let mut skip_small = scan_options.min_file_size.is_some();
+                    if ui.checkbox(&mut skip_small, "Skip files under").changed() {
+                        scan_options.min_file_size = skip_small.then_some(1024 * kilobyte);
+                    }
+                    if let Some(size) = &mut scan_options.min_file_size {
+                        let suffix = if *binary_units { " KiB" } else { " kB" };
+                        let mut kb = *size / kilobyte;
+                        if ui.add(egui::DragValue::new(&mut kb).suffix(suffix)).changed() {
+                            *size = kb * kilobyte;
+                        }
+                    }
+                });
+                ui.checkbox(&mut scan_options.two_pass, "Show progress")
+                    .on_hover_text("Counts directories first, which makes the scan slower");
+                ui.horizontal(|ui| {
+                    let mut limit_errors = scan_options.max_errors.is_some();
+                    if ui
+                        .checkbox(&mut limit_errors, "Stop after errors")
+                        .changed()
+                    {
+                        scan_options.max_errors = limit_errors.then_some(1000);
+                    }
+                    if let Some(errors) = &mut scan_options.max_errors {
+                        ui.add(egui::DragValue::new(errors).clamp_range(0..=1_000_000));
+                    }
+                });
+                ui.checkbox(&mut scan_options.stable_order, "Stable order")
+                    .on_hover_text(
+                        "List errors and exported results by path, so that the same directories \
+                         always give the same output",
+                    );
+                ui.horizontal(|ui| {
+                    let mut limit_time = scan_options.max_duration.is_some();
+                    let limit = ui
+                        .checkbox(&mut limit_time, "Time limit")
+                        .on_hover_text("Stop after this long and show what was found so far");
+                    if limit.changed() {
+                        scan_options.max_duration = limit_time.then_some(Duration::from_secs(60));
+                    }
+                    if let Some(duration) = &mut scan_options.max_duration {
+                        let mut secs = duration.as_secs();
+                        let field = egui::DragValue::new(&mut secs)
+                            .clamp_range(1..=86400)
+                            .suffix(" s");
+                        if ui.add(field).changed() {
+                            *duration = Duration::from_secs(secs);
+                        }
+                    }
+                });
+
+                ui.separator();
+                ui.strong("Performance");
+                ui.horizontal(|ui| {
+                    ui.label("Threads");
+                    ui.add(egui::DragValue::new(&mut scan_options.threads).clamp_range(0..=256))
+                        .on_hover_text("0 uses one per CPU core");
+                    ui.label("Update every");
+                    ui.add(
+                        egui::DragValue::new(update_interval_ms)
+                            .clamp_range(10..=5000)
+                            .suffix(" ms"),
+                    );
+                });
+                ui.checkbox(confirm_stop, "Confirm stop")
+                    .on_hover_text(format!(
+                        "Ask before stopping a scan of {CONFIRM_STOP_AFTER} or more directories"
+                    ));
+                ui.checkbox(refresh_on_focus, "Refresh on focus").on_hover_text(
+                    "Scan the shown directory again whenever the window is switched back to, \
+                     which may take long for large trees",
+                );
+                let clear = ui.button("Clear cache").on_hover_text(
+                    "Forget directories read by earlier scans, the next one reads everything again",
+                );
+                if clear.clicked() {
+                    cache.lock().unwrap().clear();
                 }
+
+                ui.separator();
+                reset = ui
+                    .button("Reset to defaults")
+                    .on_hover_text("Set all options above back to how they were on the first start")
+                    .clicked();
+            });
+        if reset {
+            self.reset_settings();
+        }
+
+        if !ctx.input(|i| i.raw.hovered_files.is_empty()) {
+            let layer = egui::LayerId::new(egui::Order::Foreground, egui::Id::new("drop_hint"));
+            let painter = ctx.layer_painter(layer);
+            let screen = ctx.screen_rect();
+            painter.rect_filled(screen, 0.0, egui::Color32::from_black_alpha(192));
+            painter.text(
+                screen.center(),
+                egui::Align2::CENTER_CENTER,
+                "Drop a folder here",
+                egui::FontId::proportional(24.0),
+                egui::Color32::WHITE,
+            );
+        }
+    }
+}
+
+/// Moves `path` to the front of `recent`, dropping the oldest entries above `MAX_RECENT`
+fn remember(recent: &mut Vec<String>, path: &str) {
+    recent.retain(|p| p != path);
+    recent.insert(0, path.to_owned());
+    recent.truncate(MAX_RECENT);
+}
+
+/// Adds `total` to `growth` and drops the samples older than `GROWTH_WINDOW`
+fn record_growth(growth: &mut VecDeque<(Instant, u64)>, total: u64) {
+    let now = Instant::now();
+    growth.push_back((now, total));
+    while growth
+        .front()
+        .map_or(false, |&(t, _)| now.duration_since(t) > GROWTH_WINDOW)
+    {
+        growth.pop_front();
+    }
+}
+
+/// Root of the scan in `state`, if there is one
+fn shown_root(state: &ScanState) -> Option<&str> {
+    match state {
+        ScanState::Scanning(scan) => scan.roots.first().map(String::as_str),
+        ScanState::Done(report) => Some(&report.root),
+        ScanState::Idle | ScanState::Error(_) => None,
+    }
+}
+
+/// Root, directories read, total size and time taken of the scan in `state`
+fn status_bar(ui: &mut egui::Ui, state: &ScanState, two_pass: bool, binary: bool) {
+    ui.horizontal(|ui| match state {
+        ScanState::Scanning(Scan {
+            roots,
+            total,
+            count,
+            expected,
+            started,
+            growth,
+            ..
+        }) => {
+            // Keeps repainting itself, so it moves even while no results arrive
+            ui.spinner();
+            ui.label(elide_middle(&roots.join(", "), MAX_PATH_CHARS));
+            ui.separator();
+            match expected {
+                Some(expected) => ui.label(format!("{count} of {expected} directories")),
+                // Counting may give up before the time limit, then sizing goes on without it
+                None if two_pass && *count == 0 => ui.label("Counting directories..."),
+                None => ui.label(format!("{count} directories")),
+            };
+            ui.separator();
+            ui.label(ByteSize(total.bytes).to_string_as(binary));
+            let now = Instant::now();
+            let points: Vec<_> = growth
+                .iter()
+                .map(|&(t, bytes)| (-now.duration_since(t).as_secs_f32(), bytes as f32))
+                .collect();
+            let window = GROWTH_WINDOW.as_secs();
+            chart::sparkline(ui, &points)
+                .on_hover_text(format!("Total size over the last {window} seconds"));
+            ui.separator();
+            let secs = started.elapsed().as_secs_f64();
+            ui.label(format!("{secs:.1}s"));
+            if *count > 0 && secs > 0.0 {
+                let dirs = *count as f64 / secs;
+                let bytes = ByteSize((total.bytes as f64 / secs) as u64).to_string_as(binary);
+                ui.weak(format!("{dirs:.0} directories/s, {bytes}/s"));
+            }
+        }
+        ScanState::Done(Report {
+            root,
+            total,
+            count,
+            elapsed,
+            partial,
+            time_limited,
+            ..
+        }) => {
+            ui.label(elide_middle(root, MAX_PATH_CHARS));
+            ui.separator();
+            ui.label(format!("{count} directories"));
+            ui.separator();
+            ui.label(ByteSize(total.bytes).to_string_as(binary));
+            ui.separator();
+            let secs = elapsed.as_secs_f32();
+            if *time_limited {
+                ui.label(format!("Time limit reached after {secs:.1}s"));
+            } else if *partial {
+                ui.label(format!("Stopped after {secs:.1}s"));
+            } else {
+                ui.label(format!("Done in {secs:.1}s"));
             }
+        }
+        _ => {}
+    });
+}
+
+/// How long ago `time` was, in minutes, if it was at least a minute ago
+fn age(time: SystemTime) -> Option<String> {
+    let minutes = time.elapsed().ok()?.as_secs() / 60;
+    let age = humantime::format_duration(Duration::from_secs(minutes * 60));
+    (minutes > 0).then(|| age.to_string())
+}
+
+/// Shortens `path` to `max` characters by replacing its middle with "…". The last component
+/// gets up to two thirds of the room and the start of the path the rest.
+fn elide_middle(path: &str, max: usize) -> String {
+    let chars: Vec<_> = path.chars().collect();
+    if chars.len() <= max {
+        return path.to_owned();
+    }
+
+    let room = max.saturating_sub(1);
+    let leaf = path.rsplit(['/', '\\']).next().unwrap_or_default();
+    // The separator before the leaf shows that something was left out in between
+    let tail = (leaf.chars().count() + 1).min(room - room / 3);
+    let head = room - tail;
+    let mut elided: String = chars[..head].iter().collect();
+    elided.push('…');
+    elided.extend(&chars[chars.len() - tail..]);
+    elided
+}
+
+/// Splits `path` into segments for the breadcrumb bar, each with the path of the ancestor it
+/// stands for. Both `/` and `\` are separators, so Windows paths are split on any platform.
+fn breadcrumbs(path: &str) -> Vec<(&str, &str)> {
+    let is_separator = |c: char| c == '/' || c == '\\';
+    let mut crumbs = Vec::new();
+
+    let relative = path.trim_start_matches(is_separator);
+    let root = &path[..path.len() - relative.len()];
+    if !root.is_empty() {
+        crumbs.push((root, root));
+    }
+
+    let mut end = root.len();
+    for segment in relative.split(is_separator) {
+        end += segment.len();
+        if !segment.is_empty() {
+            let mut ancestor = &path[..end];
+            // A bare drive is its current directory, not its root
+            if crumbs.is_empty() && segment.ends_with(':') && path[end..].starts_with(is_separator)
+            {
+                ancestor = &path[..end + 1];
+            }
+            crumbs.push((segment, ancestor));
+        }
+        end += 1; // Separator
+    }
+
+    crumbs
+}
+
+/// Cancels a running scan, the results so far are kept as partial ones
+fn stop_scan(state: &mut ScanState) {
+    if let ScanState::Scanning(scan) = state {
+        scan.cancel.store(true, Ordering::Relaxed);
+        // There would be nothing to show
+        if scan.count == 0 && scan.results.is_empty() {
+            *state = ScanState::Error(ScanError::Cancelled);
+            return;
+        }
+        finish_scan(state, None, false);
+    }
+}
+
+/// Turns a running scan into a report of its results. `count` is the number of directories
+/// if the scan finished, otherwise the results so far are reported as partial. Scans that
+/// finished because they were `time_limited` are partial as well.
+fn finish_scan(state: &mut ScanState, count: Option<u64>, time_limited: bool) {
+    let ScanState::Scanning(scan) = state else {
+        return;
+    };
+    let root = scan.roots[0].clone();
+    #[cfg(not(target_arch = "wasm32"))]
+    let disk = system::disk_space(Path::new(&root)).ok();
+    #[cfg(target_arch = "wasm32")]
+    let disk = None;
+    // They arrive in the order the threads read them
+    if scan.stable_order {
+        scan.errors.sort();
+    }
+    *state = ScanState::Done(Report {
+        root,
+        results: std::mem::take(&mut scan.results),
+        total: scan.total,
+        errors: std::mem::take(&mut scan.errors),
+        denied: scan.denied,
+        stable_order: scan.stable_order,
+        extensions: std::mem::take(&mut scan.extensions),
+        links: std::mem::take(&mut scan.links),
+        files: std::mem::take(&mut scan.files),
+        count: count.unwrap_or(scan.count),
+        elapsed: scan.started.elapsed(),
+        disk,
+        finished: SystemTime::now(),
+        partial: count.is_none() || time_limited,
+        time_limited,
+    });
+}
+
+/// Text of an estimate made clear to be one
+fn describe_estimate(result: io::Result<scan::Estimate>, binary: bool) -> String {
+    match result {
+        Ok(e) => {
+            let size = ByteSize(e.usage.bytes).to_string_as(binary);
+            let secs = e.duration.as_secs_f64().ceil() as u64;
+            format!(
+                "Estimated ~{size} in ~{} directories, a scan takes ~{}s (from {} samples)",
+                e.dirs,
+                secs.max(1),
+                e.samples
+            )
+        }
+        Err(e) => format!("Can't estimate: {e}"),
+    }
+}
+
+/// Closest ancestor of the missing `path` that still exists, to scan instead
+fn existing_ancestor(path: &str) -> Option<String> {
+    Path::new(path)
+        .ancestors()
+        .skip(1)
+        .find(|a| !a.as_os_str().is_empty() && a.is_dir())
+        .map(|a| a.to_string_lossy().into_owned())
+}
+
+/// Button to scan the same path again
+fn refresh_button(ui: &mut egui::Ui) -> bool {
+    ui.button("⟳ Refresh")
+        .on_hover_text("Scan the same directory again, without using the cache (F5)")
+        .clicked()
+}
+
+/// Asks the user for a destination and calls `write` with it, cancelling the dialog is not an error
+#[cfg(not(target_arch = "wasm32"))]
+fn save_with_dialog<F>(file_name: &str, filter: &str, write: F) -> Result<(), String>
+where
+    F: FnOnce(&std::path::Path) -> std::io::Result<()>,
+{
+    let extension = file_name.rsplit('.').next().unwrap_or_default();
+    let file = rfd::FileDialog::new()
+        .add_filter(filter, &[extension])
+        .set_file_name(file_name)
+        .save_file();
+
+    match file {
+        Some(file) => write(&file).map_err(|e| format!("Failed to write {}: {e}", file.display())),
+        None => Ok(()),
+    }
+}
+
+/// File the directory cache is kept in between runs
+#[cfg(not(target_arch = "wasm32"))]
+fn cache_file() -> Option<PathBuf> {
+    Some(
+        dirs_next::cache_dir()?
+            .join("dirscan")
+            .join("dir_cache.json"),
+    )
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn load_cache(file: &Path) -> io::Result<Cache> {
+    let reader = io::BufReader::new(std::fs::File::open(file)?);
+    Ok(serde_json::from_reader(reader)?)
+}
+
+/// Writes `cache` to `file` through a temporary file, so that an interrupted write doesn't
+/// leave half of it behind
+#[cfg(not(target_arch = "wasm32"))]
+fn save_cache(file: &Path, cache: &Cache) -> io::Result<()> {
+    use std::io::Write;
+
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let temporary = file.with_extension("json.tmp");
+    let mut writer = io::BufWriter::new(std::fs::File::create(&temporary)?);
+    serde_json::to_writer(&mut writer, cache)?;
+    writer.flush()?;
+    drop(writer);
+    std::fs::rename(temporary, file)
+}
+
+/// Results selected for display
+#[derive(Default)]
+pub struct Visible {
+    pub rows: Vec<DirStat>,
+    // Sum of all results matching the filter, including the ones not shown
+    pub total: Usage,
+    // Number and size of matching results hidden for being smaller than the minimum
+    pub hidden: (usize, u64),
+    // Number of results left out by the limit and the rest of `total` besides the shown and
+    // hidden ones, which also covers results a top-only scan never sent
+    pub other: (usize, Usage),
+    // Rows that changed after this are marked
+    pub changed_after: Option<SystemTime>,
+    // Differences to an earlier scan, boxed to keep running scans small
+    pub compared: Option<Box<Comparison>>,
+}
+
+/// Shown results compared to an earlier scan of the same root
+pub struct Comparison {
+    // Sizes of the rows in the earlier scan, rows missing in it are new
+    pub before: HashMap<String, u64>,
+    // Results of the earlier scan that are gone now, largest first
+    pub removed: Vec<(String, u64)>,
+}
+
+impl Visible {
+    /// Compares the rows out of `results` to `before`, the results of an earlier scan
+    fn compare(&mut self, results: &Results, before: &Results) {
+        let sizes = self.rows.iter().filter_map(|d| {
+            let size = before.get(&d.path)?;
+            Some((d.path.clone(), size.bytes))
         });
+        let mut removed: Vec<_> = before
+            .iter()
+            .filter(|(p, _)| !results.contains_key(*p))
+            .map(|(p, s)| (p.clone(), s.bytes))
+            .collect();
+        removed.sort_by(|(p1, s1), (p2, s2)| s2.cmp(s1).then_with(|| p1.cmp(p2)));
+
+        self.compared = Some(Box::new(Comparison {
+            before: sizes.collect(),
+            removed,
+        }));
     }
 }
 
-fn sort_results<'a, I>(iter: I) -> Vec<FinalEntry>
+/// Selects results containing `filter` (ignoring case) if it isn't empty and at least
+/// `min_size` bytes large. `total` is the sum of all results.
+fn visible_results(
+    results: &Results,
+    total: Usage,
+    filter: &str,
+    min_size: u64,
+    limit: usize,
+    order: SortOrder,
+) -> Visible {
+    let (matching, total): (Vec<_>, _) = if filter.is_empty() {
+        (results.iter().collect(), total)
+    } else {
+        let filter = filter.to_lowercase();
+        let matching: Vec<_> = results
+            .iter()
+            .filter(|(p, _)| p.to_lowercase().contains(&filter))
+            .collect();
+        let total = matching.iter().map(|(_, s)| **s).sum();
+        (matching, total)
+    };
+
+    let (shown, small): (Vec<_>, Vec<_>) =
+        matching.into_iter().partition(|(_, s)| s.bytes >= min_size);
+    let hidden = (small.len(), small.iter().map(|(_, s)| s.bytes).sum());
+    let count = shown.len();
+    let rows = sort_results(shown.into_iter(), limit, order);
+
+    let listed: Usage = rows.iter().map(|d| d.usage).sum();
+    let small_files: u64 = small.iter().map(|(_, s)| s.files).sum();
+    let rest = Usage {
+        bytes: total.bytes.saturating_sub(listed.bytes + hidden.1),
+        files: total.files.saturating_sub(listed.files + small_files),
+        modified: None,
+    };
+    Visible {
+        changed_after: None,
+        compared: None,
+        other: (count - rows.len(), rest),
+        rows,
+        total,
+        hidden,
+    }
+}
+
+/// Picks the `limit` largest entries (all if 0) and lists them in the given order
+fn sort_results<'a, I>(iter: I, limit: usize, order: SortOrder) -> Vec<DirStat>
 where
-    I: Iterator<Item = (&'a String, &'a u64)>,
+    I: Iterator<Item = (&'a String, &'a Usage)>,
 {
-    let mut res: Vec<_> = iter.map(|(p, &s)| (p.to_owned(), s)).collect();
-    res.sort_by(|(_, a), (_, b)| b.cmp(a)); // Descending by size
-    res.truncate(10); // Keep only 10 top results
+    let mut res = scan::largest(iter, limit);
+    match (order.column, order.ascending) {
+        (SortColumn::Size, false) => {}
+        (SortColumn::Size, true) => res.reverse(),
+        (SortColumn::Path, ascending) => {
+            res.sort_by(|a, b| a.path.cmp(&b.path));
+            if !ascending {
+                res.reverse();
+            }
+        }
+    }
 
     res
 }
 
-fn display_dirs(ui: &mut egui::Ui, vec: &Vec<FinalEntry>) {
-    let total = vec.iter().map(|(_, s)| s).sum();
+/// Shows visible results as a table next to a chart of them, or as bars, depending on `view`
+fn display_results(
+    ui: &mut egui::Ui,
+    root: &str,
+    visible: &Visible,
+    order: &mut SortOrder,
+    binary: bool,
+    actions: bool,
+    view: View,
+) -> Option<RowAction> {
+    if view == View::Bars {
+        chart::bars(ui, &visible.rows, binary);
+        return None;
+    }
+    ui.horizontal_top(|ui| {
+        let highlight = match view {
+            View::List | View::Compact | View::Bars | View::Treemap | View::Extensions => None,
+            View::Pie => chart::pie(ui, &visible.rows, visible.total.bytes, binary),
+        };
+        let compact = view == View::Compact;
+        display_dirs(
+            ui, root, visible, order, binary, actions, highlight, compact,
+        )
+    })
+    .inner
+}
+
+/// Shows all results matching the filter and minimum size as a treemap, clicking one enters it
+/// if `actions` are enabled
+fn display_treemap(
+    ui: &mut egui::Ui,
+    results: &Results,
+    total: Usage,
+    filter: &str,
+    min_size: u64,
+    binary: bool,
+    actions: bool,
+) -> Option<RowAction> {
+    let all = visible_results(results, total, filter, min_size, 0, SortOrder::default());
+    chart::treemap(ui, &all.rows, binary, actions).map(RowAction::Enter)
+}
+
+/// Shows the size of files by extension matching the filter and minimum size as a table
+fn display_extensions(
+    ui: &mut egui::Ui,
+    extensions: &Extensions,
+    filter: &str,
+    min_size: u64,
+    limit: usize,
+    order: &mut SortOrder,
+    binary: bool,
+) {
+    let total = extensions.values().copied().sum();
+    let visible = visible_results(extensions, total, filter, min_size, limit, *order);
+    // Extensions are no paths, so there is nothing to join them to or act on
+    display_dirs(ui, "", &visible, order, binary, false, None, false);
+}
+
+/// Shows visible results as a table, marking the `highlight`ed row. A `compact` table only
+/// has names and sizes.
+#[allow(clippy::too_many_arguments)]
+fn display_dirs(
+    ui: &mut egui::Ui,
+    root: &str,
+    visible: &Visible,
+    order: &mut SortOrder,
+    binary: bool,
+    actions: bool,
+    highlight: Option<usize>,
+    compact: bool,
+) -> Option<RowAction> {
+    let mut action = None;
+    // Bars take a share of the room next to the names
+    let bar_width = (!compact).then(|| (ui.available_width() / 4.0).clamp(60.0, 400.0));
+
+    let spacing = if compact {
+        egui::vec2(16.0, 1.0)
+    } else {
+        ui.spacing().item_spacing
+    };
 
+    let columns = if compact { 2 } else { 5 };
     egui::Grid::new("file_grid")
-        .num_columns(3)
+        .num_columns(columns + usize::from(visible.compared.is_some()))
+        .spacing(spacing)
         .striped(true)
         .show(ui, |ui| {
-            for dir in vec {
-                ui.label(&dir.0);
-                let fraction = dir.1 as f32 / total as f32;
-                ui.add(
-                    egui::ProgressBar::new(fraction)
-                        .show_percentage()
-                        .desired_width(200.0),
-                );
-                ui.label(ByteSize(dir.1).to_string_as(true));
+            sort_header(ui, "Path", SortColumn::Path, order);
+            if !compact {
+                ui.label("");
+            }
+            sort_header(ui, "Size", SortColumn::Size, order);
+            if !compact {
+                ui.label("%");
+                ui.label("Files");
+            }
+            if visible.compared.is_some() {
+                ui.label("Change");
+            }
+            ui.end_row();
+
+            let total = visible.total;
+            for (i, dir) in visible.rows.iter().enumerate() {
+                let full_path = Path::new(root).join(&dir.path);
+                let full_path = full_path.to_string_lossy();
+                let mut name = elide_middle(&dir.path, MAX_PATH_CHARS);
+                let mut notes = Vec::new();
+                if dir.is_symlink {
+                    name = format!("↪ {name}");
+                    let target = dir.link_target.as_deref().unwrap_or("?");
+                    notes.push(format!("Links to {target}, which isn't counted"));
+                } else if !root.is_empty() {
+                    // Extensions have no root and are neither
+                    let icon = if dir.is_dir { "📁" } else { "📄" };
+                    name = format!("{icon} {name}");
+                }
+                if let (Some(after), Some(modified)) = (visible.changed_after, dir.usage.modified) {
+                    if modified > after {
+                        name = format!("{name} 🕑");
+                    }
+                }
+                if actions && dir.is_dir {
+                    notes.push("Click to scan it, right-click for more".to_owned());
+                } else if actions {
+                    notes.push("Right-click to reveal it in the file explorer".to_owned());
+                }
+                let mut name = egui::RichText::new(name);
+                if highlight == Some(i) {
+                    name = name.background_color(ui.visuals().selection.bg_fill);
+                }
+                let response = if actions && dir.is_dir {
+                    ui.link(name)
+                } else {
+                    ui.add(egui::Label::new(name).sense(egui::Sense::click()))
+                };
+                let response = response.on_hover_ui(|ui| {
+                    row_details(ui, &full_path, dir.usage, total, binary, &notes);
+                });
+                if actions && dir.is_dir && response.clicked() {
+                    action = Some(RowAction::Enter(dir.path.clone()));
+                }
+                response.context_menu(|ui| {
+                    if ui.button("📋 Copy path").clicked() {
+                        ui.output_mut(|o| o.copied_text = full_path.clone().into_owned());
+                        ui.close_menu();
+                    }
+                    // The other actions need a finished scan
+                    if !actions {
+                        return;
+                    }
+                    if !dir.is_dir {
+                        if ui.button("📂 Reveal in file explorer").clicked() {
+                            action = Some(RowAction::Reveal(dir.path.clone()));
+                        }
+                    } else if ui.button("📂 Open in file explorer").clicked() {
+                        action = Some(RowAction::Open(dir.path.clone()));
+                    }
+                    if !dir.is_dir {
+                        if action.is_some() {
+                            ui.close_menu();
+                        }
+                        return;
+                    }
+                    if ui.button("🔍 Drill down").clicked() {
+                        action = Some(RowAction::Enter(dir.path.clone()));
+                    }
+                    if ui.button("🚫 Exclude from scan").clicked() {
+                        action = Some(RowAction::Exclude(dir.path.clone()));
+                    }
+                    if action.is_some() {
+                        ui.close_menu();
+                    }
+                });
+                size_columns(ui, dir.usage, total, binary, bar_width);
+                if let Some(compared) = &visible.compared {
+                    let before = compared.before.get(&dir.path).copied();
+                    let change = size_change(before, dir.usage.bytes, binary);
+                    if before.is_none() {
+                        ui.colored_label(ui.visuals().warn_fg_color, change);
+                    } else {
+                        ui.label(change);
+                    }
+                }
                 ui.end_row();
             }
 
-            let total = ByteSize(total).to_string_as(true);
-            ui.label(format!("Total: {total}"));
+            // The rows add up to the total with the rest in one of them. The rest can be directories,
+            // files or extensions
+            let (count, other) = visible.other;
+            if other.bytes > 0 {
+                match count {
+                    0 => ui.weak("Other"),
+                    1 => ui.weak("Other (1 item)"),
+                    n => ui.weak(format!("Other ({n} items)")),
+                };
+                size_columns(ui, other, total, binary, bar_width);
+                ui.end_row();
+            }
+
+            let files = total.files;
+            let total = ByteSize(total.bytes).to_string_as(binary);
+            ui.label(format!("Total: {total} in {files} files"));
             ui.end_row();
+
+            let (count, size) = visible.hidden;
+            if count > 0 {
+                let size = ByteSize(size).to_string_as(binary);
+                ui.weak(format!("{count} smaller items hidden ({size} total)"));
+                ui.end_row();
+            }
         });
+
+    let removed = visible.compared.as_ref().map_or(&[][..], |c| &c.removed);
+    if !removed.is_empty() {
+        ui.horizontal_wrapped(|ui| {
+            ui.colored_label(ui.visuals().warn_fg_color, "Gone since the last scan:");
+            for (path, size) in removed {
+                let size = ByteSize(*size).to_string_as(binary);
+                ui.weak(format!("{path} ({size})"));
+            }
+        });
+    }
+
+    action
+}
+
+/// Full path, exact size, file count, share of `total` and last change of a row, followed by
+/// `notes` about it
+fn row_details(
+    ui: &mut egui::Ui,
+    path: &str,
+    usage: Usage,
+    total: Usage,
+    binary: bool,
+    notes: &[String],
+) {
+    ui.strong(path);
+    let size = ByteSize(usage.bytes).to_string_as(binary);
+    ui.label(format!(
+        "{size} ({} bytes) in {} files",
+        usage.bytes, usage.files
+    ));
+    if total.bytes > 0 {
+        let share = usage.bytes as f64 / total.bytes as f64 * 100.0;
+        ui.label(format!("{share:.1}% of the total"));
+    }
+    if let Some(modified) = usage.modified {
+        let ago = age(modified).map_or("just now".into(), |a| format!("{a} ago"));
+        let time = humantime::format_rfc3339_seconds(modified);
+        ui.label(format!("Last changed {ago} ({time})"));
+    }
+    for note in notes {
+        ui.weak(note.as_str());
+    }
+}
+
+/// Growth from `before` to `now` bytes, or "new" if there was nothing before
+fn size_change(before: Option<u64>, now: u64, binary: bool) -> String {
+    let Some(before) = before else {
+        return "new".to_owned();
+    };
+    match now.cmp(&before) {
+        std::cmp::Ordering::Greater => {
+            format!("▲ +{}", ByteSize(now - before).to_string_as(binary))
+        }
+        std::cmp::Ordering::Less => format!("▼ -{}", ByteSize(before - now).to_string_as(binary)),
+        std::cmp::Ordering::Equal => String::new(),
+    }
+}
+
+/// Bar, size, percentage and file count of `usage` out of `total`, or only the size without
+/// a `bar_width`
+fn size_columns(
+    ui: &mut egui::Ui,
+    usage: Usage,
+    total: Usage,
+    binary: bool,
+    bar_width: Option<f32>,
+) {
+    let size = ByteSize(usage.bytes).to_string_as(binary);
+    let Some(bar_width) = bar_width else {
+        ui.label(size);
+        return;
+    };
+
+    // Empty rows of an empty total have no share rather than NaN
+    let fraction = usage.bytes as f32 / total.bytes.max(1) as f32;
+    let percentage = format!("{}%", (fraction * 100.0) as usize);
+    // The text is at the start of the bar, on the fill once the bar is long enough
+    let text_color = if fraction > 0.2 {
+        egui::Color32::BLACK
+    } else {
+        ui.visuals().text_color()
+    };
+    ui.add(
+        egui::ProgressBar::new(fraction)
+            .text(egui::RichText::new(percentage).color(text_color))
+            .fill(chart::severity(fraction))
+            .desired_width(bar_width),
+    );
+    ui.label(size);
+    ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+        ui.label(format!("{:.1}", fraction * 100.0));
+    });
+    ui.label(usage.files.to_string());
+}
+
+/// Clickable column header, clicking the active column flips the direction
+fn sort_header(ui: &mut egui::Ui, label: &str, column: SortColumn, order: &mut SortOrder) {
+    let active = order.column == column;
+    let text = match (active, order.ascending) {
+        (false, _) => label.to_owned(),
+        (true, true) => format!("{label} ▲"),
+        (true, false) => format!("{label} ▼"),
+    };
+
+    if ui.selectable_label(active, text).clicked() {
+        if active {
+            order.ascending = !order.ascending;
+        } else {
+            // Names read naturally A to Z, sizes largest first
+            order.column = column;
+            order.ascending = column == SortColumn::Path;
+        }
+    }
+}
+
+/// Shows how much of the volume was scanned and a bar of its used and free space
+fn display_disk(ui: &mut egui::Ui, scanned: u64, capacity: u64, free: u64, binary: bool) {
+    let size = |bytes| ByteSize(bytes).to_string_as(binary);
+    ui.label(format!(
+        "Scanned {} of {} total, {} free",
+        size(scanned),
+        size(capacity),
+        size(free)
+    ));
+    let used = capacity.saturating_sub(free);
+    let fraction = used as f32 / capacity.max(1) as f32;
+    ui.add(egui::ProgressBar::new(fraction).text(format!("{} used", size(used))));
+}
+
+/// Collapsible list of directories that weren't counted because they couldn't be read
+fn display_errors(ui: &mut egui::Ui, errors: &[(String, String)], denied: u64) -> bool {
+    if errors.is_empty() {
+        return false;
+    }
+
+    let title = format!("⚠ {} directories could not be read", errors.len());
+    egui::CollapsingHeader::new(egui::RichText::new(title).color(ui.visuals().warn_fg_color))
+        .id_source("read_errors")
+        .show(ui, |ui| {
+            egui::ScrollArea::vertical()
+                .max_height(150.0)
+                .show(ui, |ui| {
+                    for (path, error) in errors {
+                        ui.label(format!("{path}: {error}"));
+                    }
+                });
+        });
+    if denied == 0 {
+        return false;
+    }
+    // The total looks plausible, so the reason has to be spelled out
+    ui.label(format!(
+        "{denied} of them weren't allowed to be read, so what's inside is missing from the total"
+    ));
+    elevation_hint(ui)
+}
+
+/// Suggests running with more rights to read what wasn't allowed. Returns whether restarting
+/// as administrator was clicked, which is only offered on Windows.
+fn elevation_hint(ui: &mut egui::Ui) -> bool {
+    #[cfg(windows)]
+    return ui
+        .horizontal(|ui| {
+            ui.weak("Running dirscan as administrator may allow it");
+            ui.button("🛡 Run as administrator").clicked()
+        })
+        .inner;
+    #[cfg(not(windows))]
+    {
+        ui.weak("Running dirscan with sudo may allow it");
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Results of one file each, named after their position in `sizes`
+    fn results(sizes: &[u64]) -> Results {
+        let named = sizes.iter().enumerate();
+        let usage = |bytes| Usage {
+            bytes,
+            files: 1,
+            modified: None,
+        };
+        named
+            .map(|(i, &s)| (format!("dir{i:02}"), usage(s)))
+            .collect()
+    }
+
+    fn sizes(sorted: &[DirStat]) -> Vec<u64> {
+        sorted.iter().map(|d| d.usage.bytes).collect()
+    }
+
+    #[test]
+    fn reset_keeps_the_path_and_results() {
+        let mut app = TemplateApp {
+            path: "/data".into(),
+            max_results: 50,
+            scan_options: ScanOptions {
+                exclude: "target".into(),
+                max_depth: Some(2),
+                ..Default::default()
+            },
+            state: ScanState::Error(ScanError::Cancelled),
+            ..Default::default()
+        };
+        app.history.leave("/previous".into());
+        app.reset_settings();
+
+        let defaults = TemplateApp::default();
+        assert_eq!(app.path, "/data");
+        assert!(matches!(app.state, ScanState::Error(_)));
+        assert_eq!(app.history.back(None).as_deref(), Some("/previous"));
+        assert_eq!(app.max_results, defaults.max_results);
+        assert_eq!(app.scan_options.exclude, defaults.scan_options.exclude);
+        assert_eq!(app.scan_options.max_depth, defaults.scan_options.max_depth);
+    }
+
+    #[test]
+    fn history_goes_back_and_forward() {
+        let mut history = History::default();
+        history.leave("/a".into());
+        history.leave("/b".into());
+
+        assert_eq!(history.back(Some("/c".into())).as_deref(), Some("/b"));
+        assert_eq!(history.back(Some("/b".into())).as_deref(), Some("/a"));
+        assert_eq!(history.back(Some("/a".into())), None);
+        assert_eq!(history.forward(Some("/a".into())).as_deref(), Some("/b"));
+        assert_eq!(history.forward.len(), 1);
+
+        // Going somewhere else drops the way forward
+        history.leave("/b".into());
+        assert!(history.forward.is_empty());
+        assert_eq!(history.back, ["/a", "/b"]);
+    }
+
+    #[test]
+    fn comparison_finds_new_and_removed_results() {
+        let before = results(&[300, 200, 100]);
+        let mut now = results(&[400, 200]);
+        now.insert("new".into(), Usage::default());
+        let mut visible = visible_results(&now, Usage::default(), "", 0, 0, SortOrder::default());
+        visible.compare(&now, &before);
+
+        let compared = visible.compared.unwrap();
+        assert_eq!(compared.before.get("dir00"), Some(&300));
+        assert_eq!(compared.before.get("new"), None);
+        assert_eq!(compared.removed, [("dir02".to_owned(), 100)]);
+    }
+
+    #[test]
+    fn size_changes() {
+        assert_eq!(size_change(None, 5, false), "new");
+        assert_eq!(size_change(Some(5), 5, false), "");
+        assert!(size_change(Some(1000), 3000, false).starts_with("▲ +"));
+        assert!(size_change(Some(3000), 1000, false).starts_with("▼ -"));
+    }
+
+    #[test]
+    fn growth_only_keeps_recent_samples() {
+        let old = Instant::now().checked_sub(GROWTH_WINDOW * 2);
+        let mut growth: VecDeque<_> = old.map(|t| (t, 10)).into_iter().collect();
+        record_growth(&mut growth, 20);
+        record_growth(&mut growth, 30);
+
+        let totals: Vec<_> = growth.iter().map(|&(_, bytes)| bytes).collect();
+        assert_eq!(totals, [20, 30]);
+    }
+
+    #[test]
+    fn breadcrumbs_of_unix_and_windows_paths() {
+        assert_eq!(
+            breadcrumbs("/home/user/"),
+            [("/", "/"), ("home", "/home"), ("user", "/home/user")]
+        );
+        assert_eq!(
+            breadcrumbs("C:\\Users\\user"),
+            [
+                ("C:", "C:\\"),
+                ("Users", "C:\\Users"),
+                ("user", "C:\\Users\\user")
+            ]
+        );
+        assert_eq!(
+            breadcrumbs("C:/a//b"),
+            [("C:", "C:/"), ("a", "C:/a"), ("b", "C:/a//b")]
+        );
+        assert!(breadcrumbs("").is_empty());
+    }
+
+    #[test]
+    fn elides_the_middle_of_long_paths() {
+        assert_eq!(elide_middle("/home/user", 10), "/home/user");
+        assert_eq!(
+            elide_middle("/home/user/projects/dirscan", 16),
+            "/home/u…/dirscan"
+        );
+        assert_eq!(
+            elide_middle("C:\\a\\a_very_long_directory_name", 16),
+            "C:\\a\\…ctory_name"
+        );
+    }
+
+    #[test]
+    fn sorts_descending_by_size() {
+        let results = results(&[3, 10, 1, 7]);
+        let sorted = sort_results(results.iter(), 0, SortOrder::default());
+        assert_eq!(sizes(&sorted), [10, 7, 3, 1]);
+    }
+
+    #[test]
+    fn ties_are_ordered_by_path() {
+        let results = results(&[5, 5, 9, 5]);
+        let sorted = sort_results(results.iter(), 0, SortOrder::default());
+        let names: Vec<_> = sorted.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(names, ["dir02", "dir00", "dir01", "dir03"]);
+
+        // Truncation keeps the same ones a full sort would list first
+        let top = sort_results(results.iter(), 2, SortOrder::default());
+        assert_eq!(top, sorted[..2]);
+    }
+
+    #[test]
+    fn truncates_to_limit() {
+        let results = results(&(1..=15).collect::<Vec<_>>());
+        let sorted = sort_results(results.iter(), 10, SortOrder::default());
+        assert_eq!(sizes(&sorted), (6..=15).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn fewer_than_limit_pass_through() {
+        let results = results(&[2, 4, 8]);
+        let sorted = sort_results(results.iter(), 10, SortOrder::default());
+        assert_eq!(sizes(&sorted), [8, 4, 2]);
+    }
+
+    #[test]
+    fn limit_boundaries() {
+        let results = results(&[1, 2, 3, 4, 5]);
+        let len = |limit| sort_results(results.iter(), limit, SortOrder::default()).len();
+        assert_eq!(len(0), 5); // No limit
+        assert_eq!(len(1), 1);
+        assert_eq!(len(5), 5);
+        assert_eq!(len(6), 5);
+    }
+
+    #[test]
+    fn top_of_a_large_cache_matches_a_full_sort() {
+        // Equal sizes too, so ties have to come out the same way
+        let sizes: Vec<_> = (0..20_000).map(|i| i * 7919 % 10_007).collect();
+        let many = results(&sizes);
+        let sorted = sort_results(many.iter(), 0, SortOrder::default());
+        let top = sort_results(many.iter(), 50, SortOrder::default());
+        assert_eq!(top[..], sorted[..50]);
+    }
+
+    #[test]
+    fn truncated_results_are_summed_as_other() {
+        let results = results(&[1, 2, 30, 40, 50]);
+        let total = results.values().copied().sum();
+        let visible = visible_results(&results, total, "", 2, 2, SortOrder::default());
+
+        assert_eq!(sizes(&visible.rows), [50, 40]);
+        assert_eq!(visible.hidden, (1, 1));
+        let other = Usage {
+            bytes: 32,
+            files: 2,
+            modified: None,
+        };
+        assert_eq!(visible.other, (2, other));
+    }
+
+    #[test]
+    fn saved_caches_load_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("dirscan/dir_cache.json");
+        let usage = Usage {
+            bytes: 10,
+            files: 1,
+            modified: Some(SystemTime::UNIX_EPOCH),
+        };
+        let cached = scan::CachedDir {
+            modified: SystemTime::UNIX_EPOCH,
+            usage,
+            extensions: Some(HashMap::from([("txt".to_owned(), usage)])),
+            subdirs: vec!["sub".to_owned()],
+            files: Some(vec!["a.txt".to_owned()]),
+        };
+        let cache = Cache::from([("/data".to_owned(), cached)]);
+        save_cache(&file, &cache).unwrap();
+
+        let loaded = load_cache(&file).unwrap();
+        let loaded = &loaded["/data"];
+        assert_eq!(loaded.usage, usage);
+        assert_eq!(loaded.subdirs, ["sub"]);
+        assert_eq!(loaded.files, Some(vec!["a.txt".to_owned()]));
+        assert!(!file.with_extension("json.tmp").exists());
+    }
+
+    #[test]
+    fn missing_paths_suggest_the_closest_existing_ancestor() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("gone/deeper");
+        let ancestor = existing_ancestor(missing.to_str().unwrap());
+        assert_eq!(ancestor.as_deref(), dir.path().to_str());
+        assert_eq!(existing_ancestor("missing"), None);
+    }
+
+    #[test]
+    fn other_orders() {
+        let results = results(&[3, 10, 1]);
+        let order = |column, ascending| SortOrder { column, ascending };
+
+        let sorted = sort_results(results.iter(), 0, order(SortColumn::Size, true));
+        assert_eq!(sizes(&sorted), [1, 3, 10]);
+        let sorted = sort_results(results.iter(), 0, order(SortColumn::Path, true));
+        assert_eq!(sizes(&sorted), [3, 10, 1]);
+        let sorted = sort_results(results.iter(), 0, order(SortColumn::Path, false));
+        assert_eq!(sizes(&sorted), [1, 10, 3]);
+    }
 }
@@ -0,0 +1,335 @@
+use std::f32::consts::TAU;
+
+use super::scan::DirStat;
+use bytesize::ByteSize;
+use egui::plot::{Bar, BarChart, Plot};
+use egui::{pos2, vec2, Align2, Color32, FontId, Pos2, Rect, Sense, Shape, Stroke, Vec2};
+
+/// Largest angle drawn as a single polygon, so that every polygon stays convex
+const MAX_PIECE: f32 = TAU / 16.0;
+
+/// Draws `rows` as slices of a pie and the rest of `total` as a gray "Other" slice.
+/// Returns the index of the row under the pointer.
+pub fn pie(ui: &mut egui::Ui, rows: &[DirStat], total: u64, binary: bool) -> Option<usize> {
+    let (rect, response) = ui.allocate_exact_size(Vec2::splat(240.0), Sense::hover());
+    if total == 0 {
+        return None;
+    }
+
+    let center = rect.center();
+    let radius = rect.width() / 2.0;
+    let shown: u64 = rows.iter().map(|d| d.usage.bytes).sum();
+    let other = total.saturating_sub(shown);
+    let slices = rows
+        .iter()
+        .map(|d| (d.path.as_str(), d.usage.bytes))
+        .chain((other > 0).then_some(("Other", other)));
+
+    // Angle clockwise from the top, if the pointer is over the pie
+    let pointer = response
+        .hover_pos()
+        .map(|p| p - center)
+        .filter(|v| v.length() <= radius)
+        .map(|v| v.x.atan2(-v.y).rem_euclid(TAU));
+
+    let painter = ui.painter();
+    let mut hovered = None;
+    let mut start = 0.0;
+    for (i, (name, size)) in slices.enumerate() {
+        let end = start + size as f32 / total as f32 * TAU;
+        let fill = if i < rows.len() {
+            color(i)
+        } else {
+            Color32::GRAY
+        };
+
+        // The hovered slice sticks out a bit
+        if pointer.map_or(false, |a| (start..end).contains(&a)) {
+            slice(painter, center, radius, start..end, fill);
+            hovered = Some((i, name, size));
+        } else {
+            slice(painter, center, radius * 0.92, start..end, fill);
+        }
+        start = end;
+    }
+
+    let (i, name, size) = hovered?;
+    let size = ByteSize(size).to_string_as(binary);
+    response.on_hover_text_at_pointer(format!("{name}\n{size}"));
+    (i < rows.len()).then_some(i)
+}
+
+/// Draws `rows`, largest first, as a squarified treemap. Returns the row clicked if `clickable`.
+pub fn treemap(
+    ui: &mut egui::Ui,
+    rows: &[DirStat],
+    binary: bool,
+    clickable: bool,
+) -> Option<String> {
+    let sense = if clickable {
+        Sense::click()
+    } else {
+        Sense::hover()
+    };
+    let (rect, response) = ui.allocate_exact_size(vec2(ui.available_width(), 300.0), sense);
+
+    // Empty directories have no area to show
+    let rows: Vec<_> = rows.iter().filter(|d| d.usage.bytes > 0).collect();
+    let sizes: Vec<_> = rows.iter().map(|d| d.usage.bytes).collect();
+    let largest = sizes.first().copied().unwrap_or_default();
+    let cells = squarify(&sizes, rect);
+
+    let painter = ui.painter_at(rect);
+    let border = Stroke::new(1.0, ui.visuals().panel_fill);
+    let pointer = response.hover_pos();
+    let mut hovered = None;
+    for (
+        DirStat {
+            path: name, usage, ..
+        },
+        cell,
+    ) in rows.into_iter().zip(cells)
+    {
+        painter.rect_filled(cell, 0.0, heat(usage.bytes as f32 / largest as f32));
+        painter.rect_stroke(cell, 0.0, border);
+        // Names only fit into big enough cells
+        if cell.width() > 60.0 && cell.height() > 16.0 {
+            painter.with_clip_rect(cell).text(
+                cell.left_top() + vec2(3.0, 2.0),
+                Align2::LEFT_TOP,
+                name,
+                FontId::proportional(12.0),
+                Color32::BLACK,
+            );
+        }
+        if pointer.map_or(false, |p| cell.contains(p)) {
+            hovered = Some((name, usage.bytes));
+        }
+    }
+
+    let (name, size) = hovered?;
+    let clicked = response.clicked();
+    let size = ByteSize(size).to_string_as(binary);
+    response.on_hover_text_at_pointer(format!("{name}\n{size}"));
+    clicked.then(|| name.clone())
+}
+
+/// Draws `rows` as horizontal bars, the first one at the top, with sizes on the axis and the
+/// exact size of the hovered bar
+pub fn bars(ui: &mut egui::Ui, rows: &[DirStat], binary: bool) {
+    // Bars go downwards from 0, so that the first one is on top
+    let bars = rows
+        .iter()
+        .enumerate()
+        .map(|(i, d)| {
+            Bar::new(-(i as f64), d.usage.bytes as f64)
+                .name(&d.path)
+                .width(0.8)
+                .fill(color(i))
+        })
+        .collect();
+    let chart = BarChart::new(bars)
+        .horizontal()
+        .element_formatter(Box::new(move |bar, _| {
+            let size = ByteSize(bar.value as u64).to_string_as(binary);
+            format!("{}\n{size}", bar.name)
+        }));
+
+    let names: Vec<_> = rows.iter().map(|d| d.path.clone()).collect();
+    Plot::new("bars")
+        .height(40.0 + 24.0 * rows.len() as f32)
+        .allow_zoom(false)
+        .allow_drag(false)
+        .allow_scroll(false)
+        .include_x(0.0)
+        .x_axis_formatter(move |x, _| {
+            if x < 0.0 {
+                String::new()
+            } else {
+                ByteSize(x as u64).to_string_as(binary)
+            }
+        })
+        .y_axis_formatter(move |y, _| {
+            // Only whole steps are bars
+            let i = -y;
+            match names.get(i as usize) {
+                Some(name) if i.fract() == 0.0 && i >= 0.0 => name.clone(),
+                _ => String::new(),
+            }
+        })
+        .show(ui, |plot| plot.bar_chart(chart));
+}
+
+/// Draws `points` of (time, value) as a small line scaled to fill its box
+pub fn sparkline(ui: &mut egui::Ui, points: &[(f32, f32)]) -> egui::Response {
+    let (rect, response) =
+        ui.allocate_exact_size(vec2(80.0, ui.spacing().interact_size.y), Sense::hover());
+    if points.len() > 1 {
+        let stroke = Stroke::new(1.5, ui.visuals().selection.bg_fill);
+        ui.painter()
+            .add(Shape::line(scale_points(points, rect.shrink(2.0)), stroke));
+    }
+    response
+}
+
+/// Maps the range of `points` onto `rect`, with larger values further up. A range without
+/// any extent is drawn in the middle.
+fn scale_points(points: &[(f32, f32)], rect: Rect) -> Vec<Pos2> {
+    let range = |values: &mut dyn Iterator<Item = f32>| {
+        values.fold((f32::MAX, f32::MIN), |(min, max), v| {
+            (min.min(v), max.max(v))
+        })
+    };
+    let (x_min, x_max) = range(&mut points.iter().map(|p| p.0));
+    let (y_min, y_max) = range(&mut points.iter().map(|p| p.1));
+    let fraction = |v: f32, min: f32, max: f32| {
+        if max > min {
+            (v - min) / (max - min)
+        } else {
+            0.5
+        }
+    };
+
+    points
+        .iter()
+        .map(|&(x, y)| {
+            pos2(
+                rect.left() + rect.width() * fraction(x, x_min, x_max),
+                rect.bottom() - rect.height() * fraction(y, y_min, y_max),
+            )
+        })
+        .collect()
+}
+
+/// Splits `rect` into rectangles with areas proportional to `sizes`, which have to be sorted
+/// largest first. Rows of rectangles are grown as long as that makes them closer to squares.
+fn squarify(sizes: &[u64], rect: Rect) -> Vec<Rect> {
+    let total: u64 = sizes.iter().sum();
+    let scale = rect.area() / total as f32;
+    let areas: Vec<_> = sizes.iter().map(|&s| s as f32 * scale).collect();
+
+    let mut cells = Vec::with_capacity(areas.len());
+    let mut rest = rect;
+    let mut start = 0;
+    while start < areas.len() {
+        let side = rest.width().min(rest.height());
+        let mut end = start + 1;
+        while end < areas.len()
+            && worst(&areas[start..=end], side) <= worst(&areas[start..end], side)
+        {
+            end += 1;
+        }
+
+        // The row goes along the shorter side of what's left
+        let row = &areas[start..end];
+        let row_area: f32 = row.iter().sum();
+        if rest.width() >= rest.height() {
+            let width = row_area / rest.height();
+            let mut y = rest.min.y;
+            for area in row {
+                let height = area / width;
+                cells.push(Rect::from_min_size(
+                    pos2(rest.min.x, y),
+                    vec2(width, height),
+                ));
+                y += height;
+            }
+            rest.min.x += width;
+        } else {
+            let height = row_area / rest.width();
+            let mut x = rest.min.x;
+            for area in row {
+                let width = area / height;
+                cells.push(Rect::from_min_size(
+                    pos2(x, rest.min.y),
+                    vec2(width, height),
+                ));
+                x += width;
+            }
+            rest.min.y += height;
+        }
+        start = end;
+    }
+
+    cells
+}
+
+/// Worst aspect ratio of a row of `areas` laid along a `side` long edge
+fn worst(areas: &[f32], side: f32) -> f32 {
+    let sum: f32 = areas.iter().sum();
+    let max = areas.iter().copied().fold(f32::MIN, f32::max);
+    let min = areas.iter().copied().fold(f32::MAX, f32::min);
+    let (side, sum) = (side * side, sum * sum);
+    (side * max / sum).max(sum / (side * min))
+}
+
+/// Color from blue for the smallest to red for the largest `fraction` of the maximum
+fn heat(fraction: f32) -> Color32 {
+    egui::ecolor::Hsva::new(0.65 * (1.0 - fraction), 0.6, 0.85, 1.0).into()
+}
+
+/// Color from green for nothing over yellow to red for all of a `fraction`, light enough for
+/// black text on it
+pub fn severity(fraction: f32) -> Color32 {
+    let hue = (1.0 - fraction.clamp(0.0, 1.0)) / 3.0;
+    egui::ecolor::Hsva::new(hue, 0.6, 0.9, 1.0).into()
+}
+
+/// Color of the `i`th slice
+pub fn color(i: usize) -> Color32 {
+    // Hues a golden ratio apart never repeat and neighbours are never close
+    let hue = (i as f32 * 0.618_034).fract();
+    egui::ecolor::Hsva::new(hue, 0.6, 0.85, 1.0).into()
+}
+
+/// Fills the part of a circle between two angles, clockwise from the top
+fn slice(
+    painter: &egui::Painter,
+    center: Pos2,
+    radius: f32,
+    angles: std::ops::Range<f32>,
+    color: Color32,
+) {
+    let pieces = ((angles.end - angles.start) / MAX_PIECE).ceil().max(1.0) as usize;
+    let step = (angles.end - angles.start) / pieces as f32;
+    for piece in 0..pieces {
+        let from = angles.start + piece as f32 * step;
+        // A few points per piece keep the arc round
+        let arc = (0..=4).map(|j| {
+            let angle = from + step * j as f32 / 4.0;
+            center + radius * vec2(angle.sin(), -angle.cos())
+        });
+        let points = std::iter::once(center).chain(arc).collect();
+        painter.add(Shape::convex_polygon(points, color, Stroke::NONE));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparkline_points_span_the_rect() {
+        let rect = Rect::from_min_size(Pos2::ZERO, vec2(80.0, 20.0));
+        let points = scale_points(&[(-10.0, 100.0), (-5.0, 300.0), (0.0, 500.0)], rect);
+        assert_eq!(points, [pos2(0.0, 20.0), pos2(40.0, 10.0), pos2(80.0, 0.0)]);
+
+        let flat = scale_points(&[(-1.0, 7.0), (0.0, 7.0)], rect);
+        assert_eq!(flat, [pos2(0.0, 10.0), pos2(80.0, 10.0)]);
+    }
+
+    #[test]
+    fn squarify_fills_the_rect_proportionally() {
+        let rect = Rect::from_min_size(Pos2::ZERO, vec2(600.0, 400.0));
+        let sizes = [500, 250, 125, 60, 40, 25];
+        let cells = squarify(&sizes, rect);
+
+        assert_eq!(cells.len(), sizes.len());
+        let total: u64 = sizes.iter().sum();
+        for (cell, size) in cells.iter().zip(sizes) {
+            let expected = rect.area() * size as f32 / total as f32;
+            assert!((cell.area() - expected).abs() < 0.1, "{cell:?} for {size}");
+            assert!(rect.expand(0.01).contains_rect(*cell), "{cell:?} outside");
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use super::app::Results;
+use super::scan::{DirStat, Usage};
+use bytesize::ByteSize;
+
+/// Writes every aggregated directory as a `path,bytes,human_readable` row, largest first or in
+/// path order if `by_path`
+pub fn write_csv(
+    file: &Path,
+    root: &str,
+    results: &Results,
+    by_path: bool,
+    binary: bool,
+) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(file)?);
+    writeln!(out, "path,bytes,human_readable")?;
+
+    for (name, size) in sorted(results, by_path) {
+        let path = Path::new(root).join(name);
+        writeln!(
+            out,
+            "{},{size},{}",
+            csv_field(&path.to_string_lossy()),
+            ByteSize(size).to_string_as(binary)
+        )?;
+    }
+
+    out.flush()
+}
+
+/// Formats `rows` as an aligned plain-text table of paths, sizes and shares of `total`, ending
+/// with the total
+pub fn text_table(rows: &[DirStat], total: Usage, binary: bool) -> String {
+    let size = |bytes| ByteSize(bytes).to_string_as(binary);
+    let total_size = size(total.bytes);
+    let sizes: Vec<_> = rows.iter().map(|d| size(d.usage.bytes)).collect();
+    let path_width = rows
+        .iter()
+        .map(|d| d.path.chars().count())
+        .fold("Total".len(), usize::max);
+    let size_width = sizes
+        .iter()
+        .map(String::len)
+        .fold(total_size.len(), usize::max);
+
+    let mut table = String::new();
+    for (DirStat { path, usage, .. }, size) in rows.iter().zip(&sizes) {
+        let percent = usage.bytes as f64 / total.bytes.max(1) as f64 * 100.0;
+        table += &format!("{path:<path_width$}  {size:>size_width$}  {percent:5.1}%\n");
+    }
+    table += &format!("{:<path_width$}  {total_size:>size_width$}\n", "Total");
+    table
+}
+
+#[derive(serde::Serialize)]
+struct JsonReport<'a> {
+    root: &'a str,
+    // Total of the whole scan, which the entries are shares of
+    total_bytes: u64,
+    directories: Vec<JsonEntry>,
+}
+
+#[derive(serde::Serialize)]
+struct JsonEntry {
+    path: String,
+    // Size of everything inside the directory
+    bytes: u64,
+}
+
+/// Writes every aggregated directory and a summary of the scan as a JSON document, largest first
+/// or in path order if `by_path`. The `total` of the scan may be more than that of the
+/// `results`, if only the largest ones were kept.
+pub fn write_json(
+    file: &Path,
+    root: &str,
+    results: &Results,
+    total: Usage,
+    by_path: bool,
+) -> io::Result<()> {
+    let directories = sorted(results, by_path)
+        .into_iter()
+        .map(|(name, bytes)| JsonEntry {
+            path: Path::new(root).join(name).to_string_lossy().into_owned(),
+            bytes,
+        })
+        .collect();
+    let report = JsonReport {
+        root,
+        total_bytes: total.bytes,
+        directories,
+    };
+
+    let mut out = BufWriter::new(File::create(file)?);
+    serde_json::to_writer_pretty(&mut out, &report)?;
+    out.flush()
+}
+
+/// Styles of the HTML report, which has to work as a single file
+const HTML_STYLE: &str = "\
+body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; }
+td, th { padding: 0.2em 0.8em; text-align: left; }
+tr:nth-child(even) { background: #f2f2f2; }
+.size { text-align: right; white-space: nowrap; }
+.bar { width: 300px; }
+.bar div { background: #4a90d9; height: 1em; }";
+
+/// Writes every aggregated directory with a bar of its share of the total as a standalone HTML
+/// page, largest first or in path order if `by_path`, along with the root, the `total` of the
+/// scan and when the report was made
+pub fn write_html(
+    file: &Path,
+    root: &str,
+    results: &Results,
+    total: Usage,
+    by_path: bool,
+    binary: bool,
+) -> io::Result<()> {
+    let total = total.bytes;
+    let created = humantime::format_rfc3339_seconds(SystemTime::now());
+    let root = html_escape(root);
+
+    let mut out = BufWriter::new(File::create(file)?);
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<html><head><meta charset=\"utf-8\">")?;
+    writeln!(out, "<title>Disk usage of {root}</title>")?;
+    writeln!(out, "<style>{HTML_STYLE}</style></head><body>")?;
+    writeln!(out, "<h1>Disk usage of {root}</h1>")?;
+    writeln!(
+        out,
+        "<p>Total: {}, scanned {created}</p>",
+        ByteSize(total).to_string_as(binary)
+    )?;
+
+    writeln!(out, "<table><tr><th>Path</th><th></th><th>Size</th></tr>")?;
+    for (name, size) in sorted(results, by_path) {
+        let percent = size as f64 / total.max(1) as f64 * 100.0;
+        writeln!(
+            out,
+            "<tr><td>{}</td><td class=\"bar\"><div style=\"width: {percent:.1}%\"></div></td>\
+             <td class=\"size\">{}</td></tr>",
+            html_escape(name),
+            ByteSize(size).to_string_as(binary)
+        )?;
+    }
+    writeln!(out, "</table></body></html>")?;
+
+    out.flush()
+}
+
+/// Results largest first, or in path order if `by_path`. Equal sizes are ordered by path, so
+/// they don't come out in hash map order.
+fn sorted(results: &Results, by_path: bool) -> Vec<(&String, u64)> {
+    let mut res: Vec<_> = results.iter().map(|(p, u)| (p, u.bytes)).collect();
+    if by_path {
+        res.sort();
+    } else {
+        res.sort_by(|(p, a), (q, b)| b.cmp(a).then_with(|| p.cmp(q)));
+    }
+    res
+}
+
+/// Quotes a field if it contains characters that have a special meaning in CSV
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+/// Replaces characters that have a special meaning in HTML
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(bytes: u64) -> Usage {
+        Usage {
+            bytes,
+            files: 1,
+            modified: None,
+        }
+    }
+
+    #[test]
+    fn json_shares_are_of_the_whole_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("report.json");
+        // Only the largest results were kept, the scan found more
+        let results = Results::from([("big".to_owned(), usage(600))]);
+        write_json(&file, "/data", &results, usage(1000), false).unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap();
+        assert_eq!(json["total_bytes"], 1000);
+        assert_eq!(json["directories"][0]["bytes"], 600);
+        assert!(json["directories"][0].get("total_bytes").is_none());
+    }
+
+    #[test]
+    fn results_are_sorted_the_same_every_time() {
+        let results: Results = [("b", 10), ("c", 30), ("a", 10), ("d", 5)]
+            .into_iter()
+            .map(|(p, s)| (p.to_owned(), usage(s)))
+            .collect();
+        let names = |by_path| -> Vec<_> {
+            sorted(&results, by_path)
+                .into_iter()
+                .map(|(p, _)| p.as_str())
+                .collect()
+        };
+
+        assert_eq!(names(false), ["c", "a", "b", "d"]);
+        assert_eq!(names(true), ["a", "b", "c", "d"]);
+    }
+}
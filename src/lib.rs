@@ -1,5 +1,8 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
 mod app;
-mod scan;
+mod chart;
+mod export;
+pub mod scan;
+mod system;
 pub use app::TemplateApp;
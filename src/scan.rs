@@ -1,42 +1,1637 @@
-use std::collections::HashMap;
-use std::sync::{mpsc, Arc, Mutex};
+use std::any::Any;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SendError, Sender, SyncSender};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
-use super::app::{Cache, Message, ScanState};
-use dirwiz::DirWiz;
+use super::app::{Scan, ScanState};
+use ignore::gitignore::Gitignore;
 
+/// Size and number of files, and when the latest of them or their directories changed
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde::Deserialize,
+    serde::Serialize,
+)]
+pub struct Usage {
+    pub bytes: u64,
+    pub files: u64,
+    // Unknown for files inside archives and in entries of older versions
+    #[serde(default)]
+    pub modified: Option<SystemTime>,
+}
+
+impl std::ops::AddAssign for Usage {
+    fn add_assign(&mut self, other: Self) {
+        self.bytes += other.bytes;
+        self.files += other.files;
+        self.modified = self.modified.max(other.modified);
+    }
+}
+
+impl std::iter::Sum for Usage {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        let mut total = Self::default();
+        for usage in iter {
+            total += usage;
+        }
+        total
+    }
+}
+
+/// Files counted into a directory, either directly inside it or aggregated from below
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct DirStat {
+    pub path: String,
+    pub usage: Usage,
+    // A link to a directory that isn't followed, so nothing is counted into it
+    #[serde(default)]
+    pub is_symlink: bool,
+    // Where such a link points, read once when it's found
+    #[serde(default)]
+    pub link_target: Option<String>,
+    // Unset for files directly inside a root, which are listed next to its subdirectories
+    #[serde(default = "is_dir_by_default")]
+    pub is_dir: bool,
+}
+
+/// Results of older versions are all directories
+fn is_dir_by_default() -> bool {
+    true
+}
+
+/// Unfollowed links among the results and where they point, if that could be read
+pub type Links = HashMap<String, Option<String>>;
+
+/// Size of files by lowercased extension, `NO_EXTENSION` for those without one
+pub type Extensions = HashMap<String, Usage>;
+
+/// Key of files without an extension in `Extensions`
+pub const NO_EXTENSION: &str = "(none)";
+
+/// Previously read contents of a single directory
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub struct CachedDir {
+    // Modification time of the directory when it was read
+    pub modified: SystemTime,
+    // Files directly inside the directory
+    pub usage: Usage,
+    // The same files by extension, missing from entries of older versions which are read again
+    #[serde(default)]
+    pub extensions: Option<Extensions>,
+    // Names of immediate subdirectories
+    pub subdirs: Vec<String>,
+    // Names of the files in `usage`, which are checked for changes before the entry is used.
+    // Entries of older versions don't have them and are read again.
+    #[serde(default)]
+    pub files: Option<Vec<String>>,
+}
+
+/// Directory contents keyed by directory path
+pub type Cache = HashMap<String, CachedDir>;
+
+/// How many messages the worker can get ahead of the UI before it waits for it to catch up
+const MAX_PENDING_MESSAGES: usize = 16;
+
+/// Longest the UI waits to show newly sent results
+const REPAINT_DELAY: Duration = Duration::from_millis(50);
+
+/// Entries read from one directory between updates of how far the walk got in it
+const READING_UPDATE_EVERY: u64 = 10_000;
+
+pub enum Message {
+    // Directories with the files directly inside them
+    Intermediate(Vec<DirStat>),
+    Progress { current: String, count: u64 },
+    // Entries read so far from a directory that takes long to read, sent when nothing else
+    // arrived for a while
+    Reading { dir: String, entries: u64 },
+    // Files read since the last message by extension, merged into a total over the scan
+    Extensions(Extensions),
+    // Number of directories the scan is going to process, if they were counted first
+    Counted(u64),
+    // Directories that couldn't be read and why
+    Errors(Vec<(String, io::Error)>),
+    // Number of directories processed in total, and whether the scan stopped there because it
+    // took longer than `ScanOptions::max_duration`
+    Done { count: u64, time_limited: bool },
+    // Largest results aggregated like `result_key` does and the total of all of them, sent
+    // instead of `Intermediate` if `Scanner::top` is set
+    Top { rows: Vec<DirStat>, total: Usage },
+    // The scan stopped because of a bug, with the panic message
+    Failed(String),
+    // The scan stopped after more than `Scanner::max_errors` directories couldn't be read
+    TooManyErrors(u64),
+}
+
+/// Why a scan couldn't start or didn't finish
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScanError {
+    // A root doesn't exist
+    NotFound(String),
+    // A root isn't allowed to be read
+    PermissionDenied(String),
+    // A root is a file or something else that can't be walked
+    NotADirectory(String),
+    // A root can't be opened for another reason
+    Io(String, io::ErrorKind),
+    // The scan stopped because of a bug, with the panic message
+    Failed(String),
+    // More than `ScanOptions::max_errors` directories couldn't be read
+    TooManyErrors(u64),
+    // The scan was stopped before anything was read
+    Cancelled,
+    // Exporting the results failed, with the file and why
+    Export(String),
+}
+
+impl ScanError {
+    /// Error of opening the root `path`
+    pub fn open(path: &str, e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => Self::NotFound(path.to_owned()),
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied(path.to_owned()),
+            kind => Self::Io(path.to_owned(), kind),
+        }
+    }
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(path) => write!(f, "{path} doesn't exist"),
+            Self::PermissionDenied(path) => write!(f, "Not allowed to read {path}"),
+            Self::NotADirectory(path) => write!(f, "{path} is not a directory"),
+            Self::Io(path, kind) => write!(f, "Can't open {path}: {kind}"),
+            Self::Failed(e) => write!(f, "Scan failed: {e}"),
+            Self::TooManyErrors(n) => write!(f, "Aborted after {n} read errors"),
+            Self::Cancelled => write!(f, "Scan stopped before anything was read"),
+            Self::Export(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ScanError {}
+
+/// Extrapolated size of a tree, see `Scanner::estimate`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Estimate {
+    pub usage: Usage,
+    pub dirs: u64,
+    // How long a full scan would take at the rate the samples were read
+    pub duration: Duration,
+    // Number of paths sampled
+    pub samples: u64,
+}
+
+/// What a scan reads and how, kept apart from how its results are delivered
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct ScanOptions {
+    // Deepest level of the results, deeper directories are counted into their ancestor at it.
+    // Results are the top-level directories if unset.
+    pub max_depth: Option<usize>,
+    // Comma-separated patterns of directories to skip
+    pub exclude: String,
+    // Directories skipped with everything inside them, as absolute paths
+    pub excluded_dirs: Vec<PathBuf>,
+    // Skip what `.gitignore` files found during the scan ignore
+    pub respect_gitignore: bool,
+    // Threads reading directories in parallel, 0 for one per CPU core
+    pub threads: usize,
+    // Count directories before sizing them, so that the progress is known
+    pub two_pass: bool,
+    // Walk into linked directories and count linked files. Links back to a directory that is
+    // being walked are skipped, but content linked from elsewhere is counted twice
+    pub follow_symlinks: bool,
+    // Don't walk into directories on other filesystems than the root, like mount points of
+    // other disks or `/proc`. Only has an effect on Unix
+    pub one_filesystem: bool,
+    // List the files inside zip and tar archives as if the archives were directories, their
+    // sizes uncompressed
+    pub archives: bool,
+    // Count files and directories that are hidden, see `is_hidden`
+    pub include_hidden: bool,
+    // Count the space allocated for files on disk instead of their length
+    pub on_disk: bool,
+    // Leave out files smaller than this many bytes
+    pub min_file_size: Option<u64>,
+    // Stop once more directories than this couldn't be read
+    pub max_errors: Option<u64>,
+    // Stop after this long and report what was read so far
+    pub max_duration: Option<Duration>,
+    // Order what a finished scan reports by path instead of the order it was read in, so that
+    // the same tree always gives the same output
+    pub stable_order: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: None,
+            exclude: String::new(),
+            excluded_dirs: Vec::new(),
+            respect_gitignore: false,
+            threads: 0,
+            two_pass: false,
+            follow_symlinks: false,
+            one_filesystem: false,
+            archives: false,
+            include_hidden: true,
+            on_disk: false,
+            min_file_size: None,
+            max_errors: None,
+            max_duration: None,
+            stable_order: false,
+        }
+    }
+}
+
+/// Scans directory trees independently of the UI
+#[derive(Clone)]
+pub struct Scanner {
+    pub cache: Arc<Mutex<Cache>>,
+    pub options: ScanOptions,
+    // How often collected results are sent
+    pub flush_interval: Duration,
+    // Aggregate results while scanning and only send this many largest ones, which keeps
+    // messages small for huge trees but leaves out the rest of the results
+    pub top: Option<usize>,
+    // Read every directory from disk, even the cached ones, and cache them anew
+    pub refresh: bool,
+}
+
+impl Default for Scanner {
+    fn default() -> Self {
+        Self::new(&ScanOptions::default())
+    }
+}
+
+impl Scanner {
+    /// Scanner with `options` and the default delivery of results
+    pub fn new(options: &ScanOptions) -> Self {
+        Self {
+            cache: Default::default(),
+            options: options.clone(),
+            flush_interval: Duration::from_millis(100),
+            top: None,
+            refresh: false,
+        }
+    }
+
+    /// Scans `roots` like `run`, but reports to callbacks instead of a channel:
+    /// `on_progress` gets each batch of directories read, with the files directly inside them,
+    /// and `on_done` the number of directories once all of them are read. Neither is called
+    /// once `cancel` is set. Unreadable directories are skipped and `top` is ignored.
+    ///
+    /// Blocks until the scan is finished or cancelled. Directories are read on other threads,
+    /// but both callbacks run on the calling one.
+    pub fn scan_with(
+        &self,
+        roots: &[String],
+        cancel: &AtomicBool,
+        mut on_progress: impl FnMut(&[DirStat]),
+        on_done: impl FnOnce(u64),
+    ) {
+        let scanner = Self {
+            top: None,
+            ..self.clone()
+        };
+        let (tx, rx) = mpsc::sync_channel(MAX_PENDING_MESSAGES);
+        let mut on_done = Some(on_done);
+        // Panics of the scan are resumed when the scope ends
+        thread::scope(|scope| {
+            // Dropping `tx` at the end ends the loop below, the receiver outlives it
+            scope.spawn(move || scanner.run(roots, &tx, cancel, || {}));
+            for message in rx {
+                match message {
+                    Message::Intermediate(stats) => on_progress(&stats),
+                    Message::Done { count, .. } => {
+                        if let Some(on_done) = on_done.take() {
+                            on_done(count);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    /// Scans `root` on the calling thread without any channels and returns its top-level
+    /// subdirectories with everything inside them, largest first. Unreadable subdirectories
+    /// are skipped, settings about how results are delivered don't apply.
+    ///
+    /// Fails if `root` itself can't be read.
+    pub fn scan_sync(&self, root: &str) -> io::Result<Vec<DirStat>> {
+        let walk = self.walk(root, true);
+        let roots = [root.to_owned()];
+        let mut results: HashMap<String, Usage> = HashMap::new();
+        let mut files = HashSet::new();
+        let mut failed = None;
+        walk.run_sync(|(path, entry, is_dir)| match entry {
+            Ok((usage, _)) => {
+                if let Some(key) = result_key(&roots, &path, self.options.max_depth) {
+                    if !is_dir {
+                        files.insert(key.clone());
+                    }
+                    *results.entry(key).or_default() += usage;
+                }
+                true
+            }
+            // Nothing below the root was read
+            Err(e) if path == walk.root => {
+                failed = Some(e);
+                false
+            }
+            Err(_) => true,
+        });
+
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        let links: Links = self
+            .unfollowed_links(root)
+            .into_iter()
+            .filter_map(|link| {
+                let key = result_key(&roots, Path::new(&link.path), self.options.max_depth)?;
+                Some((key, link.link_target))
+            })
+            .collect();
+        for link in links.keys() {
+            results.entry(link.clone()).or_default();
+        }
+        let mut rows = largest(results.iter(), 0);
+        mark_links(&mut rows, &links);
+        mark_files(&mut rows, &files);
+        if self.options.stable_order {
+            rows.sort_by(|a, b| a.path.cmp(&b.path));
+        }
+        Ok(rows)
+    }
+
+    /// Guesses the size of `roots` without scanning them fully, by reading random paths from
+    /// each root down to a directory without subdirectories for about `budget`. Every directory
+    /// on a path stands for as many as there are at its depth if the tree was as wide
+    /// everywhere as along the path, which averages out to the right totals over many paths.
+    ///
+    /// Fails if a root can't be read. `max_depth`, `.gitignore` files and `two_pass` are
+    /// ignored.
+    pub fn estimate(&self, roots: &[String], budget: Duration) -> io::Result<Estimate> {
+        let started = Instant::now();
+        let mut random = XorShift::seeded();
+        let mut estimate = Estimate::default();
+        let mut reads = 0;
+        for (i, root) in roots.iter().enumerate() {
+            // Files directly in the root are sampled like all others
+            let walk = DirWalk {
+                root_files: None,
+                ..self.walk(root, true)
+            };
+            // Every root gets its share of the budget and at least one path
+            let until = budget * (i as u32 + 1) / roots.len() as u32;
+            let (mut usage, mut dirs, mut samples) = ([0.0; 2], 0.0, 0);
+            while samples == 0 || started.elapsed() < until {
+                let mut dir = PathBuf::from(root);
+                let mut weight = 1.0;
+                loop {
+                    let (found, _, subdirs, _) = match walk.read_dir(&dir, &[]) {
+                        Ok(contents) => contents,
+                        Err(e) if dir == walk.root => return Err(e),
+                        // The path ends where it can't go on
+                        Err(_) => break,
+                    };
+                    reads += 1;
+                    usage[0] += weight * found.bytes as f64;
+                    usage[1] += weight * found.files as f64;
+                    dirs += weight;
+                    estimate.usage.modified = estimate.usage.modified.max(found.modified);
+
+                    let subdirs: Vec<_> = subdirs
+                        .into_iter()
+                        .map(|name| dir.join(name))
+                        .filter(|d| !walk.excludes.matches(d, &walk.root))
+                        .collect();
+                    if subdirs.is_empty() {
+                        break;
+                    }
+                    weight *= subdirs.len() as f64;
+                    dir = subdirs[random.next() as usize % subdirs.len()].clone();
+                }
+                samples += 1;
+            }
+            let samples_f = samples as f64;
+            estimate.usage.bytes += (usage[0] / samples_f) as u64;
+            estimate.usage.files += (usage[1] / samples_f) as u64;
+            estimate.dirs += (dirs / samples_f).round() as u64;
+            estimate.samples += samples;
+        }
+
+        // Directories on sampled paths take about as long to read as the others
+        let per_dir = started.elapsed().as_secs_f64() / f64::from(reads.max(1));
+        let threads = match self.options.threads {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        let secs = per_dir * estimate.dirs as f64 / threads as f64;
+        estimate.duration = Duration::from_secs_f64(secs);
+        Ok(estimate)
+    }
+
+    /// Links to directories directly inside `root` that are left out for not following
+    /// symlinks, as empty results
+    fn unfollowed_links(&self, root: &str) -> Vec<DirStat> {
+        let entries = match fs::read_dir(root) {
+            Ok(entries) if !self.options.follow_symlinks => entries,
+            _ => return Vec::new(),
+        };
+        let excludes = self.excludes();
+        entries
+            .flatten()
+            .filter(|e| e.file_type().map_or(false, |t| t.is_symlink()))
+            .filter(|e| self.options.include_hidden || !is_hidden(e))
+            .filter(|e| !excludes.matches(&e.path(), Path::new(root)))
+            .filter(|e| e.path().is_dir())
+            .map(|e| DirStat {
+                path: e.path().to_string_lossy().into_owned(),
+                usage: Usage::default(),
+                is_symlink: true,
+                link_target: fs::read_link(e.path())
+                    .ok()
+                    .map(|t| t.to_string_lossy().into_owned()),
+                is_dir: true,
+            })
+            .collect()
+    }
+
+    /// Walks `roots` one after another as a single scan, sending the results to `tx` and
+    /// calling `notify` after each batch.
+    /// Blocks until the walk is finished, `cancel` is set or the receiver is dropped.
+    /// Panics of the walk are resumed here.
+    pub fn run(
+        &self,
+        roots: &[String],
+        tx: &SyncSender<Message>,
+        cancel: &AtomicBool,
+        notify: impl Fn(),
+    ) -> Result<(), SendError<Message>> {
+        // Counting directories is part of the time taken too
+        let started = Instant::now();
+        let deadline = self.options.max_duration.map(|d| started + d);
+        // Counting gives up halfway through the time limit, so that sizing gets the rest
+        let count_deadline = self.options.max_duration.map(|d| started + d / 2);
+        if self.options.two_pass {
+            let (tx_dirs, rx_dirs) = mpsc::channel();
+            let walker = self.spawn_walks(roots, false, tx_dirs, &Reading::default());
+
+            let mut dirs = 0;
+            let counted = loop {
+                if count_deadline.map_or(false, |d| Instant::now() >= d) {
+                    break false;
+                }
+                let received = rx_dirs.recv_timeout(self.flush_interval);
+                if cancel.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+                match received {
+                    Ok(_) => dirs += 1,
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break true,
+                }
+            };
+            // An unfinished count stops at the next directory it reports, and sizing goes on
+            // without knowing how many there are
+            drop(rx_dirs);
+            if counted {
+                // A panicking walk ends just like a finished one otherwise
+                if let Err(panic) = walker.join() {
+                    panic::resume_unwind(panic);
+                }
+                // Every directory gets an entry, growing the cache for each of them means
+                // rehashing it over and over
+                let mut cache = self.cache.lock().unwrap();
+                let missing = (dirs as usize).saturating_sub(cache.len());
+                cache.reserve(missing);
+                drop(cache);
+                tx.send(Message::Counted(dirs))?;
+                notify();
+            }
+        }
+
+        let (tx_dirs, rx_dirs) = mpsc::channel();
+        let reading = Reading::default();
+        let walker = self.spawn_walks(roots, true, tx_dirs, &reading);
+
+        let mut batch = match self.top {
+            Some(limit) => Batch::Top {
+                roots: roots.to_vec(),
+                max_depth: self.options.max_depth,
+                limit,
+                results: HashMap::new(),
+                total: Usage::default(),
+                links: Links::new(),
+                files: HashSet::new(),
+            },
+            None => Batch::All(Vec::new()),
+        };
+        // The walk doesn't report what it doesn't walk into
+        for root in roots {
+            for link in self.unfollowed_links(root) {
+                batch.add(link);
+            }
+        }
+        let forwarded = self.forward(rx_dirs, &reading, deadline, batch, tx, cancel, &notify)?;
+        let Some((count, time_limited)) = forwarded else {
+            return Ok(());
+        };
+        // A walk that was cut short may still be stuck in a huge directory
+        if !time_limited {
+            if let Err(panic) = walker.join() {
+                panic::resume_unwind(panic);
+            }
+        }
+        tx.send(Message::Done {
+            count,
+            time_limited,
+        })?;
+        notify();
+        Ok(())
+    }
+
+    /// Batches walk entries into messages for the UI sent every `flush_interval`, until the
+    /// walk is finished or cancelled, or more than `max_errors` entries are errors. While no
+    /// entries arrive, how far the walk got in a huge directory is sent from `reading`.
+    /// Returns the number of entries if the walk finished or got to the `deadline`, and
+    /// whether it got there. Returning drops `entries`, which stops the walk too.
+    #[allow(clippy::too_many_arguments)]
+    fn forward(
+        &self,
+        entries: Receiver<WalkEntry>,
+        reading: &Reading,
+        deadline: Option<Instant>,
+        mut batch: Batch,
+        tx: &SyncSender<Message>,
+        cancel: &AtomicBool,
+        notify: impl Fn(),
+    ) -> Result<Option<(u64, bool)>, SendError<Message>> {
+        let mut start = Instant::now();
+        let mut errors = Vec::new();
+        let mut extensions = Extensions::new();
+        let mut count = 0;
+        let mut failed = 0;
+        let mut time_limited = false;
+        loop {
+            // What arrived so far is still sent, the rest is left out
+            if deadline.map_or(false, |d| Instant::now() >= d) {
+                time_limited = true;
+                break;
+            }
+            let received = entries.recv_timeout(self.flush_interval);
+            // Scan was stopped by the user, nobody is waiting for results anymore
+            if cancel.load(Ordering::Relaxed) {
+                return Ok(None);
+            }
+            let (p, s, is_dir) = match received {
+                Ok(entry) => entry,
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some((dir, entries)) = reading.lock().unwrap().take() {
+                        let dir = dir.to_string_lossy().into_owned();
+                        tx.send(Message::Reading { dir, entries })?;
+                        notify();
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            count += u64::from(is_dir);
+            // Invalid UTF-8 is replaced, so such directories are still counted in the total
+            match s {
+                Ok((usage, files)) => {
+                    batch.add(DirStat {
+                        path: p.to_string_lossy().into_owned(),
+                        usage,
+                        is_symlink: false,
+                        link_target: None,
+                        is_dir,
+                    });
+                    for (extension, usage) in files {
+                        *extensions.entry(extension).or_default() += usage;
+                    }
+                }
+                Err(e) => {
+                    errors.push((p.to_string_lossy().into_owned(), e));
+                    failed += 1;
+                    // Such a scan is most likely pointed at the wrong place and hardly finds
+                    // anything
+                    if self.options.max_errors.map_or(false, |max| failed > max) {
+                        tx.send(Message::TooManyErrors(failed))?;
+                        notify();
+                        return Ok(None);
+                    }
+                }
+            }
+            if start.elapsed() > self.flush_interval {
+                let current = p.to_string_lossy().into_owned();
+                tx.send(Message::Progress { current, count })?;
+                if !errors.is_empty() {
+                    tx.send(Message::Errors(std::mem::take(&mut errors)))?;
+                }
+                if let Some(message) = batch.take() {
+                    tx.send(message)?;
+                }
+                if !extensions.is_empty() {
+                    tx.send(Message::Extensions(std::mem::take(&mut extensions)))?;
+                }
+                notify();
+                start = Instant::now();
+            }
+        }
+
+        // Entries collected since the last flush would be lost otherwise
+        if let Some(message) = batch.take() {
+            tx.send(message)?;
+        }
+        if !extensions.is_empty() {
+            tx.send(Message::Extensions(extensions))?;
+        }
+        if !errors.is_empty() {
+            tx.send(Message::Errors(errors))?;
+        }
+
+        Ok(Some((count, time_limited)))
+    }
+
+    /// Walks `roots` with these settings on a new thread, skipping file sizes unless `sizes`
+    /// is set. Once `tx` is disconnected the remaining roots are skipped too.
+    fn spawn_walks(
+        &self,
+        roots: &[String],
+        sizes: bool,
+        tx: Sender<WalkEntry>,
+        reading: &Reading,
+    ) -> thread::JoinHandle<()> {
+        let walks: Vec<_> = roots
+            .iter()
+            .map(|root| DirWalk {
+                reading: reading.clone(),
+                ..self.walk(root, sizes)
+            })
+            .collect();
+        let threads = self.options.threads;
+        thread::spawn(move || {
+            for walk in walks {
+                walk.run(threads, tx.clone());
+            }
+        })
+    }
+
+    fn excludes(&self) -> Excludes {
+        Excludes::new(&self.options.exclude, &self.options.excluded_dirs)
+    }
+
+    fn walk(&self, root: &str, sizes: bool) -> DirWalk {
+        DirWalk {
+            root: PathBuf::from(root),
+            cache: self.cache.clone(),
+            refresh: self.refresh,
+            max_depth: self.options.max_depth,
+            excludes: self.excludes(),
+            respect_gitignore: self.options.respect_gitignore,
+            sizes,
+            follow_symlinks: self.options.follow_symlinks,
+            device: self
+                .options
+                .one_filesystem
+                .then(|| device(Path::new(root)))
+                .flatten(),
+            visited: Default::default(),
+            archives: self.options.archives,
+            reading: Reading::default(),
+            root_files: sizes.then(Default::default),
+            hidden: self.options.include_hidden,
+            on_disk: self.options.on_disk,
+            min_file_size: self.options.min_file_size,
+            #[cfg(windows)]
+            cluster: fs2::allocation_granularity(root).unwrap_or(4096),
+        }
+    }
+}
+
+/// Expands `~` and environment variables in a typed or pasted `path`, makes it absolute and
+/// trims trailing separators. Variables are written as `%VAR%` on Windows and as `$VAR` or
+/// `${VAR}` elsewhere, unknown ones are left as they are.
+pub fn normalize_path(path: &str) -> String {
+    let path = path.trim();
+    if path.is_empty() {
+        return String::new();
+    }
+
+    let mut expanded = expand_vars(path);
+    let home = dirs_next::home_dir();
+    if let (Some(rest), Some(home)) = (expanded.strip_prefix('~'), home) {
+        if rest.is_empty() || rest.starts_with(['/', '\\']) {
+            expanded = format!("{}{rest}", home.display());
+        }
+    }
+
+    let mut absolute = PathBuf::from(expanded);
+    if absolute.is_relative() {
+        if let Ok(dir) = env::current_dir() {
+            absolute = dir.join(absolute);
+        }
+    }
+
+    let mut normalized = absolute.to_string_lossy().into_owned();
+    // The separator is part of roots like `/` and `C:\`
+    while normalized.ends_with(['/', '\\'])
+        && normalized.len() > 1
+        && !normalized.ends_with(":\\")
+        && !normalized.ends_with(":/")
+    {
+        normalized.pop();
+    }
+    normalized
+}
+
+/// Replaces references to environment variables in `text` with their values
+fn expand_vars(text: &str) -> String {
+    #[cfg(windows)]
+    const MARKER: char = '%';
+    #[cfg(not(windows))]
+    const MARKER: char = '$';
+
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(MARKER) {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let (name, len) = var_reference(rest);
+        match env::var(name).ok().filter(|_| !name.is_empty()) {
+            Some(value) => expanded.push_str(&value),
+            None => expanded.push_str(&rest[..len]),
+        }
+        rest = &rest[len..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Name of the variable referenced at the start of `text`, which starts with the marker, and
+/// the length of the reference. The name is empty if it isn't a reference after all.
+fn var_reference(text: &str) -> (&str, usize) {
+    if cfg!(windows) {
+        return match text[1..].find('%') {
+            Some(end) => (&text[1..end + 1], end + 2),
+            None => ("", 1),
+        };
+    }
+
+    if let Some(braced) = text.strip_prefix("${") {
+        return match braced.find('}') {
+            Some(end) => (&braced[..end], end + 3),
+            None => ("", 1),
+        };
+    }
+    let name = text[1..]
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .map_or(&text[1..], |end| &text[1..end + 1]);
+    (name, name.len() + 1)
+}
+
+/// Cheap pseudo-random numbers, good enough to pick samples
+struct XorShift(u64);
+
+impl XorShift {
+    fn seeded() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        // The state must never be 0
+        Self(u64::from(nanos) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// Starts scanning `roots` together in the background, the results are delivered through
+/// `state`
 pub fn scan_directory(
     ctx: &egui::Context,
     state: &mut ScanState,
-    path: &str,
-    cache: Arc<Mutex<Cache>>,
+    roots: Vec<String>,
+    scanner: Scanner,
 ) {
-    let (tx_total, rx_total) = mpsc::channel();
-    *state = ScanState::Scanning((rx_total, HashMap::new()));
+    // Otherwise the walk would just yield nothing and silently "complete"
+    for path in &roots {
+        match fs::metadata(path) {
+            Err(e) => {
+                *state = ScanState::Error(ScanError::open(path, &e));
+                return;
+            }
+            Ok(meta) if !meta.is_dir() => {
+                *state = ScanState::Error(ScanError::NotADirectory(path.clone()));
+                return;
+            }
+            Ok(_) => {}
+        }
+    }
 
-    // Not used right now
-    let mut cache = cache.lock().unwrap();
-    cache.insert("Test".to_string(), 2);
+    let (tx_total, rx_total) = mpsc::sync_channel(MAX_PENDING_MESSAGES);
+    let cancel = Arc::new(AtomicBool::new(false));
+    *state = ScanState::Scanning(Scan {
+        roots: roots.clone(),
+        rx: rx_total,
+        results: HashMap::new(),
+        total: Usage::default(),
+        cancel: cancel.clone(),
+        current: String::new(),
+        count: 0,
+        expected: None,
+        errors: Vec::new(),
+        denied: 0,
+        stable_order: scanner.options.stable_order,
+        max_depth: scanner.options.max_depth,
+        extensions: Extensions::new(),
+        links: Links::new(),
+        files: HashSet::new(),
+        top: Default::default(),
+        top_for: None,
+        started: Instant::now(),
+        growth: Default::default(),
+    });
 
     let ctx = ctx.clone();
-    let dirwiz = DirWiz::new(path).into_iter();
     thread::spawn(move || {
-        let mut start = Instant::now();
-        let mut intermediate = Vec::new();
-        for (p, s) in dirwiz {
-            intermediate.push((p.to_str().unwrap().to_owned(), s));
-            if start.elapsed() > Duration::from_millis(100) {
-                tx_total
-                    .send(Message::Intermediate(intermediate.clone()))
-                    .unwrap();
-                ctx.request_repaint();
-                intermediate.clear();
-                start = Instant::now();
+        // Repaints requested for batches sent in quick succession are merged into one
+        let notify = || ctx.request_repaint_after(REPAINT_DELAY);
+        // Otherwise the UI would wait for the results forever
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            scanner.run(&roots, &tx_total, &cancel, notify)
+        }));
+        // Sending only fails once the scan is dropped, then nobody needs the rest
+        if let Err(panic) = result {
+            let _ = tx_total.send(Message::Failed(panic_message(&*panic)));
+            notify();
+        }
+    });
+}
+
+/// Text of a panic raised with a message, which is almost every one
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => (*message).to_owned(),
+        (_, Some(message)) => message.clone(),
+        _ => "unknown error".to_owned(),
+    }
+}
+
+/// Results collected by `Scanner::forward` to be sent with the next flush
+enum Batch {
+    // Directories read since the last flush
+    All(Vec<DirStat>),
+    // Everything read so far, aggregated, of which the `limit` largest are sent
+    Top {
+        roots: Vec<String>,
+        // Deepest level of the results, see `result_key`
+        max_depth: Option<usize>,
+        limit: usize,
+        results: HashMap<String, Usage>,
+        total: Usage,
+        // Results that are unfollowed links
+        links: Links,
+        // Results that are files
+        files: HashSet<String>,
+    },
+}
+
+impl Batch {
+    fn add(&mut self, stat: DirStat) {
+        match self {
+            Self::All(stats) => stats.push(stat),
+            Self::Top {
+                roots,
+                max_depth,
+                results,
+                total,
+                links,
+                files,
+                ..
+            } => {
+                if let Some(key) = result_key(roots, Path::new(&stat.path), *max_depth) {
+                    *results.entry(key.clone()).or_default() += stat.usage;
+                    *total += stat.usage;
+                    if !stat.is_dir {
+                        files.insert(key.clone());
+                    }
+                    if stat.is_symlink {
+                        links.insert(key, stat.link_target);
+                    }
+                }
             }
         }
+    }
+
+    /// Message with what has to be sent, if anything changed since the last one
+    fn take(&mut self) -> Option<Message> {
+        match self {
+            Self::All(stats) if stats.is_empty() => None,
+            Self::All(stats) => Some(Message::Intermediate(std::mem::take(stats))),
+            Self::Top {
+                limit,
+                results,
+                total,
+                links,
+                files,
+                ..
+            } => {
+                let mut rows = largest(results.iter(), *limit);
+                mark_links(&mut rows, links);
+                mark_files(&mut rows, files);
+                Some(Message::Top {
+                    rows,
+                    total: *total,
+                })
+            }
+        }
+    }
+}
+
+/// Name of the result `path` is counted into: its ancestor `max_depth` levels (one if unset)
+/// below the root it is in, relative to that root, or with the root in front if several `roots`
+/// are scanned together, as joining it to any root gives. `None` for the roots themselves and
+/// paths outside all of them.
+pub fn result_key(roots: &[String], path: &Path, max_depth: Option<usize>) -> Option<String> {
+    let root = roots.iter().find(|r| path.starts_with(r))?;
+    let relative = path.strip_prefix(root).ok()?;
+    let subdir: PathBuf = relative
+        .components()
+        .take_while(|c| matches!(c, Component::Normal(_)))
+        .take(max_depth.unwrap_or(1).max(1))
+        .collect();
+    if subdir.as_os_str().is_empty() {
+        return None;
+    }
+
+    if roots.len() > 1 {
+        Some(Path::new(root).join(subdir).to_string_lossy().into_owned())
+    } else {
+        Some(subdir.to_string_lossy().into_owned())
+    }
+}
+
+/// Marks `rows` whose path is one of the unfollowed `links`
+pub fn mark_links(rows: &mut [DirStat], links: &Links) {
+    for row in rows {
+        let link = links.get(&row.path);
+        row.is_symlink = link.is_some();
+        row.link_target = link.cloned().flatten();
+    }
+}
+
+/// Marks `rows` whose path is one of the `files` as not being directories
+pub fn mark_files(rows: &mut [DirStat], files: &HashSet<String>) {
+    for row in rows {
+        row.is_dir = !files.contains(&row.path);
+    }
+}
 
-        let _ = tx_total.send(Message::Done);
-        ctx.request_repaint();
+/// The `limit` largest of `results` (all if 0), ordered by size descending and ties by path
+pub fn largest<'a, I>(results: I, limit: usize) -> Vec<DirStat>
+where
+    I: Iterator<Item = (&'a String, &'a Usage)>,
+{
+    let stat = |path: &String, usage| DirStat {
+        path: path.to_owned(),
+        usage,
+        is_symlink: false,
+        link_target: None,
+        is_dir: true,
+    };
+    let mut largest: Vec<_> = if limit == 0 {
+        results.map(|(p, &s)| stat(p, s)).collect()
+    } else {
+        // The smallest kept result is on top of the heap. Of equally sized ones the last by
+        // path goes first, same as in the sort below
+        let mut heap = BinaryHeap::with_capacity(limit + 1);
+        for (p, &s) in results {
+            heap.push(Reverse((s.bytes, Reverse(p), s)));
+            if heap.len() > limit {
+                heap.pop();
+            }
+        }
+        heap.into_iter()
+            .map(|Reverse((_, Reverse(p), s))| stat(p, s))
+            .collect()
+    };
+    // Ties by path so they don't come out in hash map order
+    largest.sort_by(|a, b| {
+        let by_size = b.usage.bytes.cmp(&a.usage.bytes);
+        by_size.then_with(|| a.path.cmp(&b.path))
     });
+    largest
+}
+
+/// Whether the `files` in `dir`, last modified at `modified`, still add up to the cached
+/// `usage`, in total length and latest change
+fn files_unchanged(dir: &Path, files: &[String], modified: SystemTime, usage: Usage) -> bool {
+    let mut bytes = 0;
+    let mut latest = Some(modified);
+    for name in files {
+        // Gone or replaced by something else
+        let Ok(metadata) = fs::symlink_metadata(dir.join(name)) else {
+            return false;
+        };
+        if !metadata.is_file() {
+            return false;
+        }
+        bytes += metadata.len();
+        latest = latest.max(metadata.modified().ok());
+    }
+    bytes == usage.bytes && latest == usage.modified
+}
+
+/// Directories to skip during a walk. Patterns with `*` or `?` wildcards are matched against
+/// directory names, other patterns as a substring of the path relative to the scan root.
+/// Excluded paths are matched exactly.
+struct Excludes {
+    patterns: Vec<String>,
+    paths: Vec<PathBuf>,
+}
+
+impl Excludes {
+    /// Parses comma-separated patterns
+    fn new(patterns: &str, paths: &[PathBuf]) -> Self {
+        let patterns = patterns
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(str::to_owned)
+            .collect();
+
+        Self {
+            patterns,
+            paths: paths.to_vec(),
+        }
+    }
+
+    /// Whether `dir` below `root` is skipped
+    fn matches(&self, dir: &Path, root: &Path) -> bool {
+        if self.paths.iter().any(|p| p == dir) {
+            return true;
+        }
+        let relative = dir.strip_prefix(root).unwrap_or(dir);
+        let name = relative.file_name().unwrap_or_default().to_string_lossy();
+        let relative = relative.to_string_lossy();
+
+        self.patterns.iter().any(|pattern| {
+            if pattern.contains(['*', '?']) {
+                glob_match(pattern, &name)
+            } else {
+                relative.contains(pattern.as_str())
+            }
+        })
+    }
+}
+
+/// Matches `text` against `pattern`, where `*` is any sequence of characters and `?` any one
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<_> = pattern.chars().collect();
+    let text: Vec<_> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position right after the last `*` and the text position it was tried at
+    let mut star = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            // Let the last `*` swallow one more character and retry
+            _ => match star {
+                Some((after_star, tried)) => {
+                    star = Some((after_star, tried + 1));
+                    p = after_star;
+                    t = tried + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Directory and the files directly inside it, in total and by extension, or the error if it
+/// can't be read, and whether it is a directory. Files directly inside the root are entries of
+/// their own, so that large ones can be told apart from directories.
+type WalkEntry = (PathBuf, io::Result<(Usage, Extensions)>, bool);
+
+/// Directory that takes long to read and the number of its entries read so far
+type Reading = Arc<Mutex<Option<(PathBuf, u64)>>>;
+
+/// Walk reporting every directory below the root (including the root itself) as a `WalkEntry`.
+/// Subdirectories are read in parallel, so the order of entries is unspecified.
+///
+/// Directories deeper than `max_depth` (the root is at depth 0) are still walked, but reported
+/// as their ancestor at `max_depth`, so their files are attributed to it.
+struct DirWalk {
+    root: PathBuf,
+    cache: Arc<Mutex<Cache>>,
+    // Don't take anything from the cache, only update it
+    refresh: bool,
+    max_depth: Option<usize>,
+    excludes: Excludes,
+    // Skip files and directories ignored by `.gitignore` files found during the walk
+    respect_gitignore: bool,
+    // Sizes are reported as 0 if unset, which saves reading metadata of every file
+    sizes: bool,
+    follow_symlinks: bool,
+    // Device of the root if subdirectories on other devices are skipped
+    device: Option<u64>,
+    // Directories walked so far, only tracked when not following symlinks
+    visited: Mutex<HashSet<DirId>>,
+    archives: bool,
+    // Updated every `READING_UPDATE_EVERY` entries of a directory
+    reading: Reading,
+    // Files directly inside the root, read into here instead of the root's usage, if set
+    root_files: Option<Mutex<Vec<(PathBuf, Usage)>>>,
+    hidden: bool,
+    on_disk: bool,
+    min_file_size: Option<u64>,
+    // Allocation unit of the volume, files on Windows take up a multiple of it
+    #[cfg(windows)]
+    cluster: u64,
+}
+
+/// Directory waiting to be visited by a walk
+struct PendingDir {
+    path: PathBuf,
+    depth: usize,
+    // `.gitignore` rules of the ancestors, outermost first
+    gitignores: Vec<Arc<Gitignore>>,
+    // Canonical paths of the ancestors, only tracked when following symlinks
+    ancestors: Vec<PathBuf>,
+}
+
+impl DirWalk {
+    /// Walks the tree on `threads` threads (one per CPU core if 0), sending entries to `tx`
+    /// until everything is read or `tx` is disconnected
+    fn run(&self, threads: usize, tx: Sender<WalkEntry>) {
+        let pool = match rayon::ThreadPoolBuilder::new().num_threads(threads).build() {
+            Ok(pool) => pool,
+            Err(e) => {
+                let e = io::Error::new(io::ErrorKind::Other, e);
+                let _ = tx.send((self.root.clone(), Err(e), true));
+                return;
+            }
+        };
+
+        pool.scope(|scope| self.visit_parallel(scope, self.start(), tx));
+    }
+
+    /// Walks the tree on the calling thread, passing each directory read to `report` until
+    /// all of them are or it returns false
+    fn run_sync(&self, mut report: impl FnMut(WalkEntry) -> bool) {
+        let mut pending = vec![self.start()];
+        while let Some(dir) = pending.pop() {
+            match self.visit(dir, &mut report) {
+                Some(subdirs) => pending.extend(subdirs),
+                None => return,
+            }
+        }
+    }
+
+    fn visit_parallel<'s>(
+        &'s self,
+        scope: &rayon::Scope<'s>,
+        pending: PendingDir,
+        tx: Sender<WalkEntry>,
+    ) {
+        let Some(subdirs) = self.visit(pending, |entry| tx.send(entry).is_ok()) else {
+            return;
+        };
+        for pending in subdirs {
+            let tx = tx.clone();
+            scope.spawn(move |scope| self.visit_parallel(scope, pending, tx));
+        }
+    }
+
+    fn start(&self) -> PendingDir {
+        PendingDir {
+            path: self.root.clone(),
+            depth: 0,
+            gitignores: Vec::new(),
+            ancestors: Vec::new(),
+        }
+    }
+
+    /// Reads `pending` and passes it to `report`, returning its subdirectories that still have
+    /// to be visited. `None` once `report` returns false and the walk should stop.
+    fn visit(
+        &self,
+        pending: PendingDir,
+        mut report: impl FnMut(WalkEntry) -> bool,
+    ) -> Option<Vec<PendingDir>> {
+        let PendingDir {
+            path: dir,
+            depth,
+            mut gitignores,
+            mut ancestors,
+        } = pending;
+
+        if self.follow_symlinks {
+            // A link back to a directory that is being walked would be followed forever
+            let real = fs::canonicalize(&dir).unwrap_or_else(|_| dir.clone());
+            if ancestors.contains(&real) {
+                return Some(Vec::new());
+            }
+            ancestors.push(real);
+        } else if let Some(id) = dir_id(&dir) {
+            // Hardlinked directories, bind mounts and junctions can lead back into the tree
+            if !self.visited.lock().unwrap().insert(id) {
+                log::warn!("Skipping {}, it was already scanned", dir.display());
+                return Some(Vec::new());
+            }
+        }
+
+        if self.respect_gitignore {
+            let file = dir.join(".gitignore");
+            if file.is_file() {
+                // Lines that fail to parse are skipped, the rest still apply
+                let (gitignore, _) = Gitignore::new(file);
+                gitignores.push(Arc::new(gitignore));
+            }
+        }
+
+        let (usage, extensions, subdirs, archives) = match self.read_dir(&dir, &gitignores) {
+            Ok(contents) => contents,
+            Err(e) => return report((dir, Err(e), true)).then(Vec::new),
+        };
+
+        // The scan was stopped, don't go any deeper
+        if !report((self.reported(&dir, depth), Ok((usage, extensions)), true)) {
+            return None;
+        }
+        if let (0, Some(files)) = (depth, &self.root_files) {
+            let files = std::mem::take(&mut *files.lock().unwrap());
+            for (file, usage) in files {
+                if !report((file, Ok((usage, Extensions::new())), false)) {
+                    return None;
+                }
+            }
+        }
+
+        for archive in archives {
+            let members = match self.read_archive(&archive) {
+                Ok(members) => members,
+                Err(e) => {
+                    if !report((archive, Err(e), true)) {
+                        return None;
+                    }
+                    continue;
+                }
+            };
+            // The archive is one level below `dir` and its members further down
+            for (inner, contents) in members {
+                let below = inner
+                    .strip_prefix(&archive)
+                    .map_or(0, |p| p.components().count());
+                let reported = self.reported(&inner, depth + 1 + below);
+                if !report((reported, Ok(contents), true)) {
+                    return None;
+                }
+            }
+        }
+
+        let mut pending = Vec::with_capacity(subdirs.len());
+        for name in subdirs {
+            let subdir = dir.join(name);
+            if self.excludes.matches(&subdir, &self.root) {
+                continue;
+            }
+            // Directories whose device is unknown are read to report why
+            if let (Some(root), Some(device)) = (self.device, device(&subdir)) {
+                if device != root {
+                    continue;
+                }
+            }
+
+            pending.push(PendingDir {
+                path: subdir,
+                depth: depth + 1,
+                gitignores: gitignores.clone(),
+                ancestors: ancestors.clone(),
+            });
+        }
+        Some(pending)
+    }
+
+    /// Path `dir` at `depth` below the root is reported as, its ancestor at `max_depth` if
+    /// it's deeper
+    fn reported(&self, dir: &Path, depth: usize) -> PathBuf {
+        match self.max_depth {
+            Some(max) if depth > max => dir.ancestors().nth(depth - max).unwrap_or(dir).into(),
+            _ => dir.into(),
+        }
+    }
+
+    /// Files inside a zip or tar `archive` by the directory they are in, which is the path of
+    /// the archive followed by their directory inside it. Names leading outside are skipped.
+    fn read_archive(&self, archive: &Path) -> io::Result<HashMap<PathBuf, (Usage, Extensions)>> {
+        let mut dirs: HashMap<PathBuf, (Usage, Extensions)> = HashMap::new();
+        let mut add = |name: &Path, size: u64| {
+            if !name.components().all(|c| matches!(c, Component::Normal(_))) {
+                return;
+            }
+            let dir = match name.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => archive.join(parent),
+                _ => archive.to_path_buf(),
+            };
+            // Directories are counted even without sizes
+            let (usage, extensions) = dirs.entry(dir).or_default();
+            if self.sizes && self.min_file_size.map_or(true, |min| size >= min) {
+                let file = Usage {
+                    bytes: size,
+                    files: 1,
+                    modified: None,
+                };
+                *usage += file;
+                *extensions.entry(extension(name)).or_default() += file;
+            }
+        };
+
+        let file = fs::File::open(archive)?;
+        if extension(archive) == "zip" {
+            let mut zip = zip::ZipArchive::new(file)?;
+            for i in 0..zip.len() {
+                // Raw entries are not decompressed
+                let member = zip.by_index_raw(i)?;
+                if !member.is_dir() {
+                    add(Path::new(member.name()), member.size());
+                }
+            }
+        } else {
+            let mut tar = tar::Archive::new(file);
+            // Seeks past the contents of members instead of reading through them
+            for entry in tar.entries_with_seek()? {
+                let entry = entry?;
+                if entry.header().entry_type().is_file() {
+                    add(&entry.path()?, entry.size());
+                }
+            }
+        }
+        Ok(dirs)
+    }
+
+    /// Whether cached contents apply. They include everything but links with the length of
+    /// each file, so they can't be used when some entries are ignored, hidden or too small,
+    /// links are followed, sizes on disk are counted or archives are looked into.
+    fn uses_cache(&self, gitignores: &[Arc<Gitignore>]) -> bool {
+        gitignores.is_empty()
+            && !self.archives
+            && !self.follow_symlinks
+            && self.hidden
+            && !self.on_disk
+            && self.min_file_size.is_none()
+    }
+
+    /// Length of a file, or the space allocated for it if `on_disk` is set
+    fn file_size(&self, metadata: &fs::Metadata) -> u64 {
+        if !self.on_disk {
+            return metadata.len();
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            // Always counted in 512-byte units, whatever the block size of the filesystem
+            metadata.blocks() * 512
+        }
+        #[cfg(windows)]
+        {
+            let clusters = (metadata.len() + self.cluster - 1) / self.cluster;
+            clusters * self.cluster
+        }
+        #[cfg(not(any(unix, windows)))]
+        metadata.len()
+    }
+
+    /// Returns the files directly inside `dir` (none unless `sizes` is set), in total and by
+    /// extension, the names of its subdirectories and the archives to look into, leaving out
+    /// entries ignored by `gitignores` and hidden ones unless `hidden` is set. The latest
+    /// change counted is that of `dir` itself or of its newest file.
+    ///
+    /// A directory is only read from disk if it's missing from the cache, its modification
+    /// time has changed or one of its files changed in length or modification time. The
+    /// modification time of a directory only changes when entries are added, removed or
+    /// renamed, so the files are checked as well, which catches ones rewritten in place.
+    fn read_dir(
+        &self,
+        dir: &Path,
+        gitignores: &[Arc<Gitignore>],
+    ) -> io::Result<(Usage, Extensions, Vec<OsString>, Vec<PathBuf>)> {
+        let key = dir.to_string_lossy().into_owned();
+        let changed = fs::metadata(dir).and_then(|m| m.modified()).ok();
+        let root_files = self.root_files.as_ref().filter(|_| dir == self.root);
+        // The cache holds the files of the root in its usage
+        let modified = changed.filter(|_| self.uses_cache(gitignores) && root_files.is_none());
+
+        if let (false, Some(modified)) = (self.refresh, modified) {
+            // Files are checked without holding the lock, the other threads need it too
+            let cached = self.cache.lock().unwrap().get(&key).cloned();
+            if let Some(cached) = cached.filter(|c| c.modified == modified) {
+                // Entries of older versions lack some of the contents and are read again
+                let complete = cached.usage.modified.is_some();
+                if let (true, Some(extensions), Some(files)) =
+                    (complete, cached.extensions, &cached.files)
+                {
+                    if files_unchanged(dir, files, modified, cached.usage) {
+                        let subdirs = cached.subdirs.iter().map(OsString::from).collect();
+                        return Ok((cached.usage, extensions, subdirs, Vec::new()));
+                    }
+                }
+            }
+        }
+
+        let mut usage = Usage {
+            modified: changed,
+            ..Default::default()
+        };
+        let mut extensions = Extensions::new();
+        let mut subdirs = Vec::new();
+        let mut archives = Vec::new();
+        // Names of the files counted, unless one isn't valid UTF-8
+        let mut file_names = Some(Vec::new());
+        let mut read = 0;
+        for entry in fs::read_dir(dir)?.flatten() {
+            read += 1;
+            if read % READING_UPDATE_EVERY == 0 {
+                *self.reading.lock().unwrap() = Some((dir.to_owned(), read));
+            }
+            let path = entry.path();
+            let ignored = |is_dir| {
+                (!self.hidden && is_hidden(&entry)) || is_ignored(gitignores, &path, is_dir)
+            };
+            let (file_type, metadata) = if self.follow_symlinks {
+                // Broken links fail here and are skipped like other special files
+                let metadata = fs::metadata(&path);
+                (metadata.as_ref().map(|m| m.file_type()).ok(), metadata.ok())
+            } else {
+                (entry.file_type().ok(), None)
+            };
+            match file_type {
+                Some(t) if t.is_dir() && !ignored(true) => subdirs.push(entry.file_name()),
+                Some(t) if self.archives && t.is_file() && is_archive(&path) && !ignored(false) => {
+                    archives.push(path)
+                }
+                Some(t) if self.sizes && t.is_file() && !ignored(false) => {
+                    let metadata = metadata.or_else(|| entry.metadata().ok());
+                    let size = metadata.as_ref().map_or(0, |m| self.file_size(m));
+                    if self.min_file_size.map_or(true, |min| size >= min) {
+                        let file = Usage {
+                            bytes: size,
+                            files: 1,
+                            modified: metadata.and_then(|m| m.modified().ok()),
+                        };
+                        *extensions.entry(extension(&path)).or_default() += file;
+                        file_names = file_names.and_then(|mut names| {
+                            names.push(entry.file_name().into_string().ok()?);
+                            Some(names)
+                        });
+                        match root_files {
+                            Some(files) => files.lock().unwrap().push((path, file)),
+                            None => usage += file,
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Otherwise it would still be reported once the walk is stuck somewhere else
+        if read >= READING_UPDATE_EVERY {
+            let mut reading = self.reading.lock().unwrap();
+            if reading.as_ref().map_or(false, |(d, _)| d == dir) {
+                *reading = None;
+            }
+        }
+
+        // Directories with non-UTF-8 subdirectory names are simply read every time
+        let names: Option<Vec<_>> = subdirs
+            .iter()
+            .map(|n| n.to_str().map(str::to_owned))
+            .collect();
+        // Without sizes there is nothing worth caching
+        if let (true, Some(modified), Some(names), Some(files)) =
+            (self.sizes, modified, names, file_names)
+        {
+            let cached = CachedDir {
+                modified,
+                usage,
+                extensions: Some(extensions.clone()),
+                subdirs: names,
+                files: Some(files),
+            };
+            self.cache.lock().unwrap().insert(key, cached);
+        }
+
+        Ok((usage, extensions, subdirs, archives))
+    }
+}
+
+/// Whether `file` is an archive that can be looked into
+fn is_archive(file: &Path) -> bool {
+    matches!(extension(file).as_str(), "zip" | "tar")
+}
+
+/// Lowercased extension of `file`, the key of its size in `Extensions`
+fn extension(file: &Path) -> String {
+    match file.extension() {
+        Some(extension) => extension.to_string_lossy().to_lowercase(),
+        None => NO_EXTENSION.to_owned(),
+    }
+}
+
+/// What identifies a directory whichever path leads to it
+#[cfg(unix)]
+type DirId = (u64, u64);
+#[cfg(not(unix))]
+type DirId = PathBuf;
+
+fn dir_id(path: &Path) -> Option<DirId> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        fs::metadata(path).ok().map(|m| (m.dev(), m.ino()))
+    }
+    #[cfg(not(unix))]
+    {
+        fs::canonicalize(path).ok()
+    }
+}
+
+/// ID of the device `path` is on, `None` where that isn't known
+fn device(path: &Path) -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        fs::metadata(path).ok().map(|m| m.dev())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+/// Whether `entry` is hidden: its name starts with a dot or, on Windows, it has the hidden
+/// attribute
+fn is_hidden(entry: &fs::DirEntry) -> bool {
+    if entry.file_name().to_string_lossy().starts_with('.') {
+        return true;
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        if let Ok(metadata) = entry.metadata() {
+            return metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0;
+        }
+    }
+    false
+}
+
+/// Whether the innermost `.gitignore` rule matching `path` ignores it
+fn is_ignored(gitignores: &[Arc<Gitignore>], path: &Path, is_dir: bool) -> bool {
+    gitignores
+        .iter()
+        .rev()
+        .map(|gitignore| gitignore.matched(path, is_dir))
+        .find(|m| !m.is_none())
+        .map_or(false, |m| m.is_ignore())
 }
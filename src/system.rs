@@ -0,0 +1,84 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Opens `path` in the platform file manager
+pub fn open_in_file_manager(path: &Path) -> io::Result<()> {
+    // The directory may have been removed since it was scanned
+    if !path.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "directory no longer exists",
+        ));
+    }
+
+    #[cfg(target_os = "windows")]
+    let program = "explorer";
+    #[cfg(target_os = "macos")]
+    let program = "open";
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let program = "xdg-open";
+
+    Command::new(program).arg(path).spawn().map(|_| ())
+}
+
+/// Starts dirscan again with administrator rights, which Windows asks the user to allow
+#[cfg(windows)]
+pub fn relaunch_elevated() -> io::Result<()> {
+    let exe = std::env::current_exe()?;
+    // Quotes inside a single-quoted PowerShell string are doubled
+    let exe = exe.to_string_lossy().replace('\'', "''");
+    let status = Command::new("powershell")
+        .args(["-NoProfile", "-WindowStyle", "Hidden", "-Command"])
+        .arg(format!("Start-Process -FilePath '{exe}' -Verb RunAs"))
+        .status()?;
+    // Also if the user declined
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "not started as administrator",
+        ));
+    }
+    Ok(())
+}
+
+/// Elevating is only offered on Windows
+#[cfg(not(windows))]
+pub fn relaunch_elevated() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "only supported on Windows",
+    ))
+}
+
+/// Total and available space of the volume containing `path`, in bytes
+#[cfg(not(target_arch = "wasm32"))]
+pub fn disk_space(path: &Path) -> io::Result<(u64, u64)> {
+    Ok((fs2::total_space(path)?, fs2::available_space(path)?))
+}
+
+/// Common directories to scan, with their names: the user folders and the roots of all drives
+pub fn places() -> Vec<(String, PathBuf)> {
+    let folders = [
+        ("Home", dirs_next::home_dir()),
+        ("Desktop", dirs_next::desktop_dir()),
+        ("Downloads", dirs_next::download_dir()),
+        ("Documents", dirs_next::document_dir()),
+    ];
+    let mut places: Vec<_> = folders
+        .into_iter()
+        .filter_map(|(name, dir)| Some((name.to_owned(), dir?)))
+        .collect();
+
+    #[cfg(windows)]
+    places.extend(
+        (b'A'..=b'Z')
+            .map(|letter| format!("{}:\\", letter as char))
+            .filter(|drive| Path::new(drive).exists())
+            .map(|drive| (drive.clone(), PathBuf::from(drive))),
+    );
+    #[cfg(not(windows))]
+    places.push(("/".to_owned(), PathBuf::from("/")));
+
+    places
+}
@@ -0,0 +1,766 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use dirscan::scan::{self, Extensions, Message, ScanOptions, Scanner, Usage};
+
+/// Creates `root/a/sub` and `root/b` with files of known sizes:
+/// 5 bytes in `root`, 100 in `a`, 50 in `a/sub` and 10 in `b`
+fn fixture() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    fs::create_dir_all(root.join("a/sub")).unwrap();
+    fs::create_dir(root.join("b")).unwrap();
+    fs::write(root.join("file"), [0; 5]).unwrap();
+    fs::write(root.join("a/file"), [0; 100]).unwrap();
+    fs::write(root.join("a/sub/file"), [0; 50]).unwrap();
+    fs::write(root.join("b/file"), [0; 10]).unwrap();
+    dir
+}
+
+/// Runs `scanner` over `root` to completion and returns every message it sent
+fn run(scanner: Scanner, root: &Path, cancel: bool) -> Vec<Message> {
+    run_roots(scanner, &[root], cancel)
+}
+
+fn run_roots(scanner: Scanner, roots: &[&Path], cancel: bool) -> Vec<Message> {
+    let (tx, rx) = mpsc::sync_channel(1);
+    let roots: Vec<_> = roots
+        .iter()
+        .map(|r| r.to_str().unwrap().to_owned())
+        .collect();
+    thread::spawn(move || {
+        let cancel = AtomicBool::new(cancel);
+        scanner.run(&roots, &tx, &cancel, || {}).unwrap();
+    });
+
+    rx.into_iter().collect()
+}
+
+/// Sizes of all reported directories under `root`, relative to it. Files reported on their own
+/// count into the directory they are in.
+fn sizes(messages: &[Message], root: &Path) -> HashMap<String, u64> {
+    let mut sizes = HashMap::new();
+    for message in messages {
+        if let Message::Intermediate(entries) = message {
+            for entry in entries {
+                let path = Path::new(&entry.path);
+                let dir = if entry.is_dir {
+                    Some(path)
+                } else {
+                    path.parent()
+                };
+                let Some(Ok(relative)) = dir.map(|d| d.strip_prefix(root)) else {
+                    continue;
+                };
+                let relative = relative.to_str().unwrap().replace('\\', "/");
+                *sizes.entry(relative).or_default() += entry.usage.bytes;
+            }
+        }
+    }
+    sizes
+}
+
+fn expected(entries: &[(&str, u64)]) -> HashMap<String, u64> {
+    entries.iter().map(|&(p, s)| (p.to_owned(), s)).collect()
+}
+
+#[test]
+fn reports_every_directory() {
+    let dir = fixture();
+    let messages = run(Scanner::default(), dir.path(), false);
+
+    let expected = expected(&[("", 5), ("a", 100), ("a/sub", 50), ("b", 10)]);
+    assert_eq!(sizes(&messages, dir.path()), expected);
+    assert!(!messages.iter().any(|m| matches!(m, Message::Errors(_))));
+    assert!(matches!(
+        messages.last(),
+        Some(Message::Done { count: 4, .. })
+    ));
+
+    let files: u64 = messages
+        .iter()
+        .filter_map(|m| match m {
+            Message::Intermediate(entries) => Some(entries.iter().map(|e| e.usage.files)),
+            _ => None,
+        })
+        .flatten()
+        .sum();
+    assert_eq!(files, 4);
+}
+
+#[test]
+fn sizes_add_up_per_top_level_directory() {
+    let dir = fixture();
+    let root = dir.path();
+    fs::create_dir_all(root.join("a/sub/deeper/deepest")).unwrap();
+    fs::write(root.join("a/sub/deeper/file"), [0; 1000]).unwrap();
+    fs::write(root.join("a/sub/deeper/deepest/file"), [0; 4096]).unwrap();
+    fs::write(root.join("b/empty"), []).unwrap();
+
+    // Like the UI does: everything below a top-level directory counts into it
+    let mut top_level: HashMap<String, u64> = HashMap::new();
+    for (path, size) in sizes(&run(Scanner::default(), root, false), root) {
+        let top = path.split('/').next().unwrap().to_owned();
+        *top_level.entry(top).or_default() += size;
+    }
+
+    let expected = expected(&[("", 5), ("a", 100 + 50 + 1000 + 4096), ("b", 10)]);
+    assert_eq!(top_level, expected);
+    assert_eq!(top_level.values().sum::<u64>(), 5 + 5246 + 10);
+}
+
+#[test]
+fn callbacks_run_on_the_calling_thread() {
+    let dir = fixture();
+    let roots = [dir.path().to_str().unwrap().to_owned()];
+    let caller = thread::current().id();
+    let (mut bytes, mut done) = (0, None);
+
+    Scanner::default().scan_with(
+        &roots,
+        &AtomicBool::new(false),
+        |stats| {
+            assert_eq!(thread::current().id(), caller);
+            bytes += stats.iter().map(|s| s.usage.bytes).sum::<u64>();
+        },
+        |count| done = Some(count),
+    );
+
+    assert_eq!(bytes, 165);
+    assert_eq!(done, Some(4));
+}
+
+#[test]
+fn sync_scan_returns_top_level_directories() {
+    let dir = fixture();
+    let rows = Scanner::default()
+        .scan_sync(dir.path().to_str().unwrap())
+        .unwrap();
+
+    let rows: Vec<_> = rows
+        .iter()
+        .map(|r| (r.path.as_str(), r.usage.bytes, r.usage.files))
+        .collect();
+    assert_eq!(rows, [("a", 150, 2), ("b", 10, 1), ("file", 5, 1)]);
+}
+
+#[test]
+fn stable_order_lists_results_by_path() {
+    let dir = fixture();
+    fs::create_dir(dir.path().join("c")).unwrap();
+    fs::write(dir.path().join("c/file"), [0; 1000]).unwrap();
+    let scanner = Scanner::new(&ScanOptions {
+        stable_order: true,
+        ..Default::default()
+    });
+    let rows = scanner.scan_sync(dir.path().to_str().unwrap()).unwrap();
+
+    let rows: Vec<_> = rows
+        .iter()
+        .map(|r| (r.path.as_str(), r.usage.bytes))
+        .collect();
+    assert_eq!(rows, [("a", 150), ("b", 10), ("c", 1000), ("file", 5)]);
+}
+
+#[test]
+fn files_in_the_root_are_listed_apart_from_directories() {
+    let dir = fixture();
+    let rows = Scanner::default()
+        .scan_sync(dir.path().to_str().unwrap())
+        .unwrap();
+
+    let kinds: Vec<_> = rows.iter().map(|r| (r.path.as_str(), r.is_dir)).collect();
+    assert_eq!(kinds, [("a", true), ("b", true), ("file", false)]);
+
+    // Files further down still only count into their directory
+    let messages = run(Scanner::default(), dir.path(), false);
+    let files: Vec<_> = messages
+        .iter()
+        .filter_map(|m| match m {
+            Message::Intermediate(entries) => Some(entries.iter().filter(|e| !e.is_dir)),
+            _ => None,
+        })
+        .flatten()
+        .map(|e| {
+            Path::new(&e.path)
+                .strip_prefix(dir.path())
+                .unwrap()
+                .to_owned()
+        })
+        .collect();
+    assert_eq!(files, [Path::new("file")]);
+}
+
+#[test]
+fn the_latest_change_below_a_directory_is_kept() {
+    let dir = fixture();
+    let rows = Scanner::default()
+        .scan_sync(dir.path().to_str().unwrap())
+        .unwrap();
+
+    let latest = |path: &str| {
+        fs::metadata(dir.path().join(path))
+            .unwrap()
+            .modified()
+            .unwrap()
+    };
+    let a = rows.iter().find(|r| r.path == "a").unwrap();
+    let modified = a.usage.modified.unwrap();
+    assert!(modified >= latest("a/sub/file") && modified >= latest("a/sub"));
+}
+
+#[test]
+fn estimates_of_an_even_tree_are_exact() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    fs::write(root.join("file"), [0; 5]).unwrap();
+    for i in 0..4 {
+        fs::create_dir_all(root.join(format!("{i}/sub"))).unwrap();
+        fs::write(root.join(format!("{i}/sub/file")), [0; 10]).unwrap();
+    }
+
+    let roots = [root.to_str().unwrap().to_owned()];
+    let estimate = Scanner::default()
+        .estimate(&roots, Duration::from_millis(20))
+        .unwrap();
+    assert_eq!((estimate.usage.bytes, estimate.usage.files), (45, 5));
+    assert_eq!(estimate.dirs, 9);
+    assert!(estimate.samples > 0);
+}
+
+#[test]
+fn subdirectories_are_read_from_the_cache_of_a_parent_scan() {
+    let dir = fixture();
+    let scanner = Scanner::default();
+    scanner.scan_sync(dir.path().to_str().unwrap()).unwrap();
+
+    // Marked, so that it shows whether the entry is used
+    let sub = dir.path().join("a/sub");
+    let key = sub.to_str().unwrap().to_owned();
+    scanner
+        .cache
+        .lock()
+        .unwrap()
+        .get_mut(&key)
+        .unwrap()
+        .usage
+        .files = 99;
+    let a = dir.path().join("a");
+    let sub = |rows: Vec<scan::DirStat>| rows.into_iter().find(|r| r.path == "sub").unwrap();
+    let rows = scanner.scan_sync(a.to_str().unwrap()).unwrap();
+    assert_eq!(sub(rows).usage.files, 99);
+
+    // Refreshing reads it again and caches what it read
+    let refresh = Scanner {
+        refresh: true,
+        ..scanner.clone()
+    };
+    let rows = refresh.scan_sync(a.to_str().unwrap()).unwrap();
+    assert_eq!(sub(rows).usage.files, 1);
+    assert_eq!(scanner.cache.lock().unwrap()[&key].usage.files, 1);
+}
+
+#[test]
+fn files_rewritten_in_place_are_read_again() {
+    let dir = fixture();
+    let scanner = Scanner::default();
+    let root = dir.path().to_str().unwrap();
+    scanner.scan_sync(root).unwrap();
+
+    // Rewriting a file in place leaves the modification time of its directory alone
+    fs::write(dir.path().join("a/sub/file"), [0; 500]).unwrap();
+    let rows = scanner.scan_sync(root).unwrap();
+    let a = rows.iter().find(|r| r.path == "a").unwrap();
+    assert_eq!(a.usage.bytes, 100 + 500);
+}
+
+#[test]
+fn sync_scan_of_a_missing_directory_fails() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing");
+
+    let e = Scanner::default()
+        .scan_sync(missing.to_str().unwrap())
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+}
+
+#[test]
+fn errors_opening_a_root_keep_their_kind() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing");
+    let missing = missing.to_str().unwrap();
+
+    let e = std::fs::metadata(missing).unwrap_err();
+    assert_eq!(
+        scan::ScanError::open(missing, &e),
+        scan::ScanError::NotFound(missing.to_owned())
+    );
+    let e = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+    assert_eq!(
+        scan::ScanError::open("/root", &e),
+        scan::ScanError::PermissionDenied("/root".to_owned())
+    );
+    let e = std::io::Error::from(std::io::ErrorKind::TimedOut);
+    let e = scan::ScanError::open("/net", &e);
+    assert_eq!(
+        e,
+        scan::ScanError::Io("/net".to_owned(), std::io::ErrorKind::TimedOut)
+    );
+    assert!(e.to_string().starts_with("Can't open /net: "));
+}
+
+#[test]
+fn files_are_summed_by_extension() {
+    let dir = fixture();
+    fs::write(dir.path().join("a/x.TXT"), [0; 7]).unwrap();
+    fs::write(dir.path().join("a/sub/y.txt"), [0; 3]).unwrap();
+    let messages = run(Scanner::default(), dir.path(), false);
+
+    let mut extensions = Extensions::new();
+    for message in messages {
+        if let Message::Extensions(read) = message {
+            for (extension, usage) in read {
+                *extensions.entry(extension).or_default() += usage;
+            }
+        }
+    }
+    let extensions: HashMap<_, _> = extensions
+        .into_iter()
+        .map(|(extension, usage)| (extension, (usage.bytes, usage.files)))
+        .collect();
+    let expected = HashMap::from([
+        ("txt".to_owned(), (10, 2)),
+        (scan::NO_EXTENSION.to_owned(), (165, 4)),
+    ]);
+    assert_eq!(extensions, expected);
+}
+
+#[test]
+fn max_depth_reports_deeper_directories_as_their_ancestor() {
+    let dir = fixture();
+    let scanner = Scanner::new(&ScanOptions {
+        max_depth: Some(1),
+        ..Default::default()
+    });
+    let messages = run(scanner, dir.path(), false);
+
+    let expected = expected(&[("", 5), ("a", 150), ("b", 10)]);
+    assert_eq!(sizes(&messages, dir.path()), expected);
+}
+
+#[test]
+fn results_go_down_to_max_depth() {
+    let dir = fixture();
+    let root = dir.path();
+    fs::create_dir(root.join("a/sub/deeper")).unwrap();
+    fs::write(root.join("a/sub/deeper/file"), [0; 1000]).unwrap();
+    let scanner = Scanner::new(&ScanOptions {
+        max_depth: Some(2),
+        ..Default::default()
+    });
+
+    let rows = scanner.scan_sync(root.to_str().unwrap()).unwrap();
+    let sizes: HashMap<_, _> = rows
+        .iter()
+        .map(|r| (r.path.replace('\\', "/"), r.usage.bytes))
+        .collect();
+    let expected = expected(&[("file", 5), ("a", 100), ("a/sub", 1050), ("b", 10)]);
+    assert_eq!(sizes, expected);
+}
+
+#[test]
+fn excluded_directories_are_skipped() {
+    let dir = fixture();
+    let scanner = Scanner::new(&ScanOptions {
+        exclude: "sub, b".to_owned(),
+        ..Default::default()
+    });
+    let messages = run(scanner, dir.path(), false);
+
+    let expected = expected(&[("", 5), ("a", 100)]);
+    assert_eq!(sizes(&messages, dir.path()), expected);
+    assert!(matches!(
+        messages.last(),
+        Some(Message::Done { count: 2, .. })
+    ));
+}
+
+#[test]
+fn excluded_paths_are_skipped_with_everything_inside() {
+    let dir = fixture();
+    let scanner = Scanner::new(&ScanOptions {
+        excluded_dirs: vec![dir.path().join("a")],
+        ..Default::default()
+    });
+    let messages = run(scanner, dir.path(), false);
+
+    let expected = expected(&[("", 5), ("b", 10)]);
+    assert_eq!(sizes(&messages, dir.path()), expected);
+}
+
+#[test]
+fn scans_stop_at_the_time_limit() {
+    let dir = fixture();
+    let scanner = Scanner::new(&ScanOptions {
+        max_duration: Some(Duration::ZERO),
+        ..Default::default()
+    });
+    let messages = run(scanner, dir.path(), false);
+
+    let done = messages.last();
+    assert!(matches!(
+        done,
+        Some(Message::Done {
+            time_limited: true,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn counting_gives_up_at_the_time_limit() {
+    let dir = fixture();
+    let scanner = Scanner::new(&ScanOptions {
+        two_pass: true,
+        max_duration: Some(Duration::ZERO),
+        ..Default::default()
+    });
+    let messages = run(scanner, dir.path(), false);
+
+    assert!(!messages.iter().any(|m| matches!(m, Message::Counted(_))));
+    assert!(matches!(
+        messages.last(),
+        Some(Message::Done {
+            time_limited: true,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn hidden_entries_can_be_skipped() {
+    let dir = fixture();
+    fs::create_dir(dir.path().join(".hidden")).unwrap();
+    fs::write(dir.path().join(".hidden/file"), [0; 20]).unwrap();
+    fs::write(dir.path().join("a/.file"), [0; 30]).unwrap();
+
+    let messages = run(Scanner::default(), dir.path(), false);
+    let all = expected(&[
+        ("", 5),
+        ("a", 130),
+        ("a/sub", 50),
+        ("b", 10),
+        (".hidden", 20),
+    ]);
+    assert_eq!(sizes(&messages, dir.path()), all);
+
+    let scanner = Scanner::new(&ScanOptions {
+        include_hidden: false,
+        ..Default::default()
+    });
+    let messages = run(scanner, dir.path(), false);
+    let visible = expected(&[("", 5), ("a", 100), ("a/sub", 50), ("b", 10)]);
+    assert_eq!(sizes(&messages, dir.path()), visible);
+}
+
+#[test]
+fn archives_can_be_looked_into() {
+    let dir = fixture();
+    let root = dir.path();
+    let mut tar = tar::Builder::new(Vec::new());
+    for (name, size) in [("top.txt", 20), ("inner/file.txt", 300)] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(size as u64);
+        header.set_cksum();
+        tar.append_data(&mut header, name, &vec![0; size][..])
+            .unwrap();
+    }
+    fs::write(root.join("a/backup.tar"), tar.into_inner().unwrap()).unwrap();
+    let mut zip = zip::ZipWriter::new(fs::File::create(root.join("b/photos.zip")).unwrap());
+    let stored =
+        zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    for (name, size) in [("a.jpg", 40), ("2023/b.jpg", 700)] {
+        zip.start_file(name, stored).unwrap();
+        std::io::Write::write_all(&mut zip, &vec![1; size]).unwrap();
+    }
+    zip.finish().unwrap();
+
+    let scanner = Scanner::new(&ScanOptions {
+        archives: true,
+        ..Default::default()
+    });
+    let messages = run(scanner, root, false);
+    let expected = expected(&[
+        ("", 5),
+        ("a", 100),
+        ("a/backup.tar", 20),
+        ("a/backup.tar/inner", 300),
+        ("a/sub", 50),
+        ("b", 10),
+        ("b/photos.zip", 40),
+        ("b/photos.zip/2023", 700),
+    ]);
+    assert_eq!(sizes(&messages, root), expected);
+}
+
+#[test]
+fn too_many_errors_stop_the_scan() {
+    let dir = fixture();
+    let scanner = Scanner::new(&ScanOptions {
+        max_errors: Some(0),
+        ..Default::default()
+    });
+    let messages = run(scanner, &dir.path().join("missing"), false);
+
+    assert!(matches!(messages.last(), Some(Message::TooManyErrors(1))));
+    assert!(!messages.iter().any(|m| matches!(m, Message::Done { .. })));
+}
+
+#[test]
+fn small_files_can_be_left_out() {
+    let dir = fixture();
+    let scanner = Scanner::new(&ScanOptions {
+        min_file_size: Some(50),
+        ..Default::default()
+    });
+    let messages = run(scanner, dir.path(), false);
+
+    let expected = expected(&[("", 0), ("a", 100), ("a/sub", 50), ("b", 0)]);
+    assert_eq!(sizes(&messages, dir.path()), expected);
+}
+
+#[test]
+fn cancelled_scan_sends_nothing() {
+    let dir = fixture();
+    let messages = run(Scanner::default(), dir.path(), true);
+
+    assert!(messages.is_empty());
+}
+
+#[test]
+fn parallel_scan_matches_sequential() {
+    let dir = fixture();
+    let scan = |threads| {
+        let scanner = Scanner::new(&ScanOptions {
+            threads,
+            ..Default::default()
+        });
+        sizes(&run(scanner, dir.path(), false), dir.path())
+    };
+
+    assert_eq!(scan(1), scan(4));
+}
+
+#[test]
+fn two_pass_scan_counts_directories_first() {
+    let dir = fixture();
+    let scanner = Scanner::new(&ScanOptions {
+        two_pass: true,
+        ..Default::default()
+    });
+    let messages = run(scanner, dir.path(), false);
+
+    assert!(matches!(messages.first(), Some(Message::Counted(4))));
+    let expected = expected(&[("", 5), ("a", 100), ("a/sub", 50), ("b", 10)]);
+    assert_eq!(sizes(&messages, dir.path()), expected);
+}
+
+#[test]
+fn several_roots_are_scanned_as_one() {
+    let (first, second) = (fixture(), fixture());
+    let scanner = Scanner::new(&ScanOptions {
+        two_pass: true,
+        ..Default::default()
+    });
+    let messages = run_roots(scanner, &[first.path(), second.path()], false);
+
+    assert!(matches!(messages.first(), Some(Message::Counted(8))));
+    assert!(matches!(
+        messages.last(),
+        Some(Message::Done { count: 8, .. })
+    ));
+    for root in [first.path(), second.path()] {
+        let expected = expected(&[("", 5), ("a", 100), ("a/sub", 50), ("b", 10)]);
+        assert_eq!(sizes(&messages, root), expected);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn sparse_files_take_less_space_on_disk() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = fs::File::create(dir.path().join("sparse")).unwrap();
+    file.set_len(1 << 20).unwrap();
+
+    let apparent = sizes(&run(Scanner::default(), dir.path(), false), dir.path());
+    assert_eq!(apparent, expected(&[("", 1 << 20)]));
+
+    let scanner = Scanner::new(&ScanOptions {
+        on_disk: true,
+        ..Default::default()
+    });
+    let on_disk = sizes(&run(scanner, dir.path(), false), dir.path());
+    assert!(on_disk[""] < 1 << 20, "{on_disk:?}");
+    assert_eq!(on_disk[""] % 512, 0);
+}
+
+#[cfg(unix)]
+#[test]
+fn followed_symlinks_do_not_loop() {
+    use std::os::unix::fs::symlink;
+
+    let dir = fixture();
+    let root = dir.path();
+    symlink(root, root.join("a/loop")).unwrap();
+    symlink(root.join("b"), root.join("a/to_b")).unwrap();
+    symlink(root.join("b/file"), root.join("a/to_file")).unwrap();
+
+    let messages = run(Scanner::default(), root, false);
+    let unfollowed = expected(&[("", 5), ("a", 100), ("a/sub", 50), ("b", 10)]);
+    assert_eq!(sizes(&messages, root), unfollowed);
+
+    let scanner = Scanner::new(&ScanOptions {
+        follow_symlinks: true,
+        ..Default::default()
+    });
+    let messages = run(scanner, root, false);
+    let followed = expected(&[
+        ("", 5),
+        ("a", 110),
+        ("a/sub", 50),
+        ("a/to_b", 10),
+        ("b", 10),
+    ]);
+    assert_eq!(sizes(&messages, root), followed);
+}
+
+#[cfg(unix)]
+#[test]
+fn unfollowed_links_are_listed_as_empty_results() {
+    let dir = fixture();
+    let root = dir.path();
+    std::os::unix::fs::symlink(root.join("a"), root.join("link")).unwrap();
+
+    let rows = Scanner::default()
+        .scan_sync(root.to_str().unwrap())
+        .unwrap();
+    let link = rows.iter().find(|r| r.path == "link").unwrap();
+    assert!(link.is_symlink);
+    let target = root.join("a").to_string_lossy().into_owned();
+    assert_eq!(link.link_target, Some(target));
+    assert_eq!(link.usage, Usage::default());
+    assert!(rows
+        .iter()
+        .filter(|r| r.path != "link")
+        .all(|r| !r.is_symlink));
+
+    let scanner = Scanner::new(&ScanOptions {
+        follow_symlinks: true,
+        ..Default::default()
+    });
+    let rows = scanner.scan_sync(root.to_str().unwrap()).unwrap();
+    let link = rows.iter().find(|r| r.path == "link").unwrap();
+    assert!(!link.is_symlink);
+    assert_eq!(link.usage.bytes, 150);
+}
+
+#[cfg(unix)]
+#[test]
+fn other_filesystems_can_be_skipped() {
+    use std::os::unix::fs::{symlink, MetadataExt};
+
+    let dir = fixture();
+    let root = dir.path();
+    let device = |p: &Path| fs::metadata(p).unwrap().dev();
+    // Only a followed link can reach another filesystem without mounting one
+    if device(root) == device(Path::new("/dev")) {
+        return;
+    }
+    symlink("/dev", root.join("a/dev")).unwrap();
+
+    let scanner = Scanner::new(&ScanOptions {
+        follow_symlinks: true,
+        one_filesystem: true,
+        ..Default::default()
+    });
+    let messages = run(scanner, root, false);
+    let expected = expected(&[("", 5), ("a", 100), ("a/sub", 50), ("b", 10)]);
+    assert_eq!(sizes(&messages, root), expected);
+}
+
+#[cfg(unix)]
+#[test]
+fn typed_paths_are_normalized() {
+    std::env::set_var("DIRSCAN_TEST_DIR", "/tmp/dirscan");
+    assert_eq!(scan::normalize_path(" /tmp/dirscan// "), "/tmp/dirscan");
+    assert_eq!(
+        scan::normalize_path("$DIRSCAN_TEST_DIR/a/"),
+        "/tmp/dirscan/a"
+    );
+    assert_eq!(
+        scan::normalize_path("${DIRSCAN_TEST_DIR}b"),
+        "/tmp/dirscanb"
+    );
+    assert_eq!(
+        scan::normalize_path("/a/$DIRSCAN_UNSET"),
+        "/a/$DIRSCAN_UNSET"
+    );
+    assert_eq!(scan::normalize_path("/a/$"), "/a/$");
+    assert_eq!(scan::normalize_path("/"), "/");
+
+    let home = std::env::var("HOME").unwrap();
+    assert_eq!(scan::normalize_path("~/x"), format!("{home}/x"));
+    assert_eq!(scan::normalize_path("/a/~"), "/a/~");
+    let current = std::env::current_dir().unwrap();
+    assert_eq!(
+        scan::normalize_path("x"),
+        current.join("x").to_str().unwrap()
+    );
+}
+
+#[test]
+fn top_results_match_a_full_sort() {
+    let dir = fixture();
+    let root = dir.path();
+    for (i, size) in [70, 300, 5, 120, 120, 0, 64].into_iter().enumerate() {
+        fs::create_dir_all(root.join(format!("d{i}/nested"))).unwrap();
+        fs::write(root.join(format!("d{i}/nested/file")), vec![0; size]).unwrap();
+    }
+    let roots = [root.to_str().unwrap().to_owned()];
+
+    let mut all = HashMap::new();
+    for message in run(Scanner::default(), root, false) {
+        if let Message::Intermediate(entries) = message {
+            for entry in entries {
+                if let Some(key) = scan::result_key(&roots, Path::new(&entry.path), None) {
+                    *all.entry(key).or_default() += entry.usage;
+                }
+            }
+        }
+    }
+    let sorted = scan::largest(all.iter(), 0);
+    // The file in the root is a result of its own
+    assert_eq!(sorted.len(), 10);
+
+    let scanner = Scanner {
+        top: Some(4),
+        ..Default::default()
+    };
+    let messages = run(scanner, root, false);
+    let last_top = messages.iter().rev().find_map(|m| match m {
+        Message::Top { rows, total } => Some((rows, total)),
+        _ => None,
+    });
+    let (rows, total) = last_top.unwrap();
+    assert_eq!(rows[..], sorted[..4]);
+    assert_eq!(total.bytes, 5 + 150 + 10 + 70 + 300 + 5 + 120 + 120 + 64);
+    assert!(!messages
+        .iter()
+        .any(|m| matches!(m, Message::Intermediate(_))));
+}